@@ -23,7 +23,7 @@ fn get_latency(b: &mut Bencher) {
         .max_size_per_segment(50 * 1024 * 1024)
         .build()
         .unwrap();
-    let mut bitcask = bitcask_rs::Bitcask::new(config);
+    let mut bitcask = bitcask_rs::Bitcask::new(config).unwrap();
     let key = vec![1u8; 512];
     let vec = vec![1u8; 4096];
 
@@ -47,7 +47,7 @@ fn put_latency(b: &mut Bencher) {
         .max_size_per_segment(50 * 1024 * 1024)
         .build()
         .unwrap();
-    let mut bitcask = bitcask_rs::Bitcask::new(config);
+    let mut bitcask = bitcask_rs::Bitcask::new(config).unwrap();
     let key = vec![1u8; 512];
     let vec = vec![1u8; 4096];
 
@@ -58,3 +58,40 @@ fn put_latency(b: &mut Bencher) {
 
     fs::remove_dir_all(path).unwrap();
 }
+
+/// Read a value from a segment written before the store was reopened, so it
+/// is served from an older segment rather than the active one.
+fn older_get_latency(b: &mut Bencher, mmap_reads: bool) {
+    let id: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(16)
+        .collect();
+    let path = format!("target/benches/bench-{}.db", id);
+    let config = bitcask_rs::ConfigBuilder::default()
+        .path(PathBuf::from(&path))
+        .max_size_per_segment(50 * 1024 * 1024)
+        .mmap_reads(mmap_reads)
+        .build()
+        .unwrap();
+    let key = vec![1u8; 512];
+    let vec = vec![1u8; 4096];
+    {
+        let mut bitcask = bitcask_rs::Bitcask::new(config.clone()).unwrap();
+        bitcask.set(key.clone(), vec.clone()).unwrap();
+    }
+    let bitcask = bitcask_rs::Bitcask::open(config).unwrap();
+
+    b.iter(|| bitcask.get(&key).unwrap());
+
+    fs::remove_dir_all(path).unwrap();
+}
+
+#[bench]
+fn older_get_latency_pread(b: &mut Bencher) {
+    older_get_latency(b, false);
+}
+
+#[bench]
+fn older_get_latency_mmap(b: &mut Bencher) {
+    older_get_latency(b, true);
+}
@@ -14,14 +14,14 @@ fn main() {
         .max_size_per_segment(50 * 1024 * 1024)
         .build()
         .unwrap();
-    let mut bitcask = bitcask_rs::Bitcask::new(config);
+    let mut bitcask = bitcask_rs::Bitcask::new(config).unwrap();
     let key = vec![1u8; 512];
     let vec = vec![1u8; 4096];
 
     let set_ret = bitcask.set(key.clone(), vec.clone());
     assert!(set_ret.is_ok());
 
-    for i in 0..100000000 {
+    for _ in 0..100000000 {
         bitcask.get(&key).unwrap();
     }
     fs::remove_dir_all(path).unwrap();
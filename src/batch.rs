@@ -0,0 +1,37 @@
+use core::{Key, Value};
+use segment::RecordKind;
+
+/// A set of writes applied atomically by `Bitcask::write`: after a crash
+/// either all of them are visible or none are.
+#[derive(Default)]
+pub struct WriteBatch {
+    pub(crate) ops: Vec<(RecordKind, Key, Value)>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        WriteBatch::default()
+    }
+
+    pub fn put(&mut self, key: Key, value: Value) -> &mut Self {
+        self.ops.push((RecordKind::Put, key, value));
+        self
+    }
+
+    pub fn delete(&mut self, key: Key) -> &mut Self {
+        self.ops.push((RecordKind::Delete, key, vec![]));
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.ops.clear()
+    }
+}
@@ -0,0 +1,327 @@
+use segment::RecordKind;
+use std::hash::Hasher;
+use std::mem;
+use store::Position;
+use twox_hash::XxHash;
+
+/// Table slot that was never used.
+const EMPTY: u32 = 0;
+/// Table slot whose entry was removed. Probing continues past it.
+const REMOVED: u32 = u32::MAX;
+/// `key_len` of an entry on the free list.
+const FREE: u32 = u32::MAX;
+/// The kind is kept in the top bits of the timestamp.
+const KIND_SHIFT: u32 = 62;
+const TSTAMP_MASK: u64 = (1 << KIND_SHIFT) - 1;
+
+/// Longest key or value a compact keydir can point at, which leaves room
+/// for the rest of the record in front of the value.
+pub const MAX_LEN: u64 = u32::MAX as u64 - 64;
+
+/// A `Position` packed into 32 bit file ids, offsets and sizes, with the
+/// record kind folded into the timestamp.
+#[derive(Copy, Clone)]
+struct PackedPosition {
+    file_id: u32,
+    offset: u32,
+    /// Distance from the start of the record to its value.
+    value_offset: u32,
+    value_size: u32,
+    tstamp_kind: u64,
+    expiry: u64,
+}
+
+impl PackedPosition {
+    /// Panics if a field does not fit, which `Config::validate` rules out for
+    /// file ids and offsets, and writes for keys and values over `MAX_LEN`.
+    fn pack(pos: &Position) -> Self {
+        let narrow = |n: u64| {
+            assert!(
+                n <= u64::from(u32::MAX),
+                "position out of range for compact keydir"
+            );
+            n as u32
+        };
+        PackedPosition {
+            file_id: narrow(pos.file_id),
+            offset: narrow(pos.offset),
+            value_offset: narrow(pos.value_pos - pos.offset),
+            value_size: narrow(pos.value_size),
+            tstamp_kind: pos.tstamp & TSTAMP_MASK | u64::from(pos.kind.to_byte()) << KIND_SHIFT,
+            expiry: pos.expiry,
+        }
+    }
+
+    fn unpack(&self) -> Position {
+        let kind = RecordKind::from_byte((self.tstamp_kind >> KIND_SHIFT) as u8)
+            .expect("packed record kind");
+        Position {
+            file_id: u64::from(self.file_id),
+            offset: u64::from(self.offset),
+            value_pos: u64::from(self.offset) + u64::from(self.value_offset),
+            value_size: u64::from(self.value_size),
+            tstamp: self.tstamp_kind & TSTAMP_MASK,
+            expiry: self.expiry,
+            kind,
+        }
+    }
+}
+
+struct Entry {
+    key_offset: u64,
+    key_len: u32,
+    pos: PackedPosition,
+}
+
+/// Keydir for stores with more keys than a `HashMap` of owned keys fits in
+/// memory. Key bytes are copied back to back into one arena, positions are
+/// packed, and an open addressing table of 32 bit entry indexes replaces the
+/// hash map buckets. Costs about 60 bytes per key besides the key itself.
+pub struct CompactKeyDir {
+    arena: Vec<u8>,
+    entries: Vec<Entry>,
+    /// Indexes of removed entries, reused by later inserts.
+    free: Vec<u32>,
+    /// Entry index plus one, `EMPTY` or `REMOVED`. The length is a power of
+    /// two.
+    table: Vec<u32>,
+    len: usize,
+    /// Slots that are `REMOVED`.
+    removed: usize,
+    /// Arena bytes of removed keys.
+    garbage: usize,
+}
+
+fn hash(key: &[u8]) -> u64 {
+    let mut hasher = XxHash::with_seed(0);
+    hasher.write(key);
+    hasher.finish()
+}
+
+impl Default for CompactKeyDir {
+    fn default() -> Self {
+        CompactKeyDir {
+            arena: vec![],
+            entries: vec![],
+            free: vec![],
+            table: vec![EMPTY; 16],
+            len: 0,
+            removed: 0,
+            garbage: 0,
+        }
+    }
+}
+
+impl CompactKeyDir {
+    fn key(&self, entry: &Entry) -> &[u8] {
+        let start = entry.key_offset as usize;
+        &self.arena[start..start + entry.key_len as usize]
+    }
+
+    /// The table slot holding `key`, or the slot it would go in.
+    fn find(&self, key: &[u8]) -> (usize, bool) {
+        let mask = self.table.len() - 1;
+        let mut i = hash(key) as usize & mask;
+        let mut first_removed = None;
+        loop {
+            match self.table[i] {
+                EMPTY => return (first_removed.unwrap_or(i), false),
+                REMOVED => {
+                    first_removed = first_removed.or(Some(i));
+                }
+                slot => {
+                    if self.key(&self.entries[slot as usize - 1]) == key {
+                        return (i, true);
+                    }
+                }
+            }
+            i = (i + 1) & mask;
+        }
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<Position> {
+        match self.find(key) {
+            (i, true) => Some(self.entries[self.table[i] as usize - 1].pos.unpack()),
+            _ => None,
+        }
+    }
+
+    pub fn insert(&mut self, key: &[u8], pos: Position) -> Option<Position> {
+        let packed = PackedPosition::pack(&pos);
+        let (i, found) = self.find(key);
+        if found {
+            let entry = &mut self.entries[self.table[i] as usize - 1];
+            return Some(mem::replace(&mut entry.pos, packed).unpack());
+        }
+
+        assert!(
+            (key.len() as u64) < u64::from(FREE),
+            "key too long for compact keydir"
+        );
+        let entry = Entry {
+            key_offset: self.arena.len() as u64,
+            key_len: key.len() as u32,
+            pos: packed,
+        };
+        self.arena.extend_from_slice(key);
+        let index = match self.free.pop() {
+            Some(index) => {
+                self.entries[index as usize] = entry;
+                index
+            }
+            None => {
+                assert!(
+                    self.entries.len() < REMOVED as usize - 1,
+                    "compact keydir is full"
+                );
+                self.entries.push(entry);
+                self.entries.len() as u32 - 1
+            }
+        };
+        if self.table[i] == REMOVED {
+            self.removed -= 1;
+        }
+        self.table[i] = index + 1;
+        self.len += 1;
+        if (self.len + self.removed) * 4 > self.table.len() * 3 {
+            self.rehash();
+        }
+        None
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> Option<Position> {
+        let (i, found) = self.find(key);
+        if !found {
+            return None;
+        }
+        let index = self.table[i] - 1;
+        self.table[i] = REMOVED;
+        self.removed += 1;
+        self.len -= 1;
+        let entry = &mut self.entries[index as usize];
+        self.garbage += entry.key_len as usize;
+        entry.key_len = FREE;
+        let pos = entry.pos.unpack();
+        self.free.push(index);
+        if self.garbage > self.arena.len() / 2 {
+            self.compact_arena();
+        }
+        Some(pos)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item = (&'a [u8], Position)> + 'a {
+        self.entries
+            .iter()
+            .filter(|entry| entry.key_len != FREE)
+            .map(move |entry| (self.key(entry), entry.pos.unpack()))
+    }
+
+    /// Bytes allocated for the keys, entries and table.
+    pub fn memory_usage(&self) -> usize {
+        self.arena.capacity()
+            + self.entries.capacity() * mem::size_of::<Entry>()
+            + (self.free.capacity() + self.table.capacity()) * mem::size_of::<u32>()
+    }
+
+    /// Rebuild the table at twice the live entries, dropping `REMOVED` slots.
+    fn rehash(&mut self) {
+        let size = (self.len * 2).next_power_of_two().max(16);
+        let table = mem::replace(&mut self.table, vec![EMPTY; size]);
+        self.removed = 0;
+        let mask = size - 1;
+        for slot in table {
+            if slot == EMPTY || slot == REMOVED {
+                continue;
+            }
+            let mut i = hash(self.key(&self.entries[slot as usize - 1])) as usize & mask;
+            while self.table[i] != EMPTY {
+                i = (i + 1) & mask;
+            }
+            self.table[i] = slot;
+        }
+    }
+
+    /// Copy the live keys into a fresh arena, leaving out removed ones.
+    fn compact_arena(&mut self) {
+        let mut arena = Vec::with_capacity(self.arena.len() - self.garbage);
+        for entry in &mut self.entries {
+            if entry.key_len == FREE {
+                continue;
+            }
+            let start = entry.key_offset as usize;
+            entry.key_offset = arena.len() as u64;
+            arena.extend_from_slice(&self.arena[start..start + entry.key_len as usize]);
+        }
+        self.arena = arena;
+        self.garbage = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn position(file_id: u64, kind: RecordKind) -> Position {
+        Position {
+            file_id,
+            offset: 100,
+            value_pos: 120,
+            value_size: 7,
+            tstamp: 1_540_000_000_000,
+            expiry: 0,
+            kind,
+        }
+    }
+
+    #[test]
+    fn it_packs_positions() {
+        let mut keydir = CompactKeyDir::default();
+        let pos = position(3, RecordKind::Delete);
+        assert_eq!(keydir.insert(b"a", pos), None);
+        assert_eq!(keydir.get(b"a"), Some(pos));
+        assert_eq!(keydir.get(b"b"), None);
+
+        let newer = position(4, RecordKind::Put);
+        assert_eq!(keydir.insert(b"a", newer), Some(pos));
+        assert_eq!(keydir.get(b"a"), Some(newer));
+        assert_eq!(keydir.len(), 1);
+    }
+
+    #[test]
+    fn it_grows_and_reuses_removed_entries() {
+        let mut keydir = CompactKeyDir::default();
+        for i in 0..1000u32 {
+            keydir.insert(
+                format!("key-{}", i).as_bytes(),
+                position(u64::from(i), RecordKind::Put),
+            );
+        }
+        for i in (0..1000u32).filter(|i| i % 3 == 0) {
+            assert!(keydir.remove(format!("key-{}", i).as_bytes()).is_some());
+        }
+        for i in 1000..1100u32 {
+            keydir.insert(
+                format!("key-{}", i).as_bytes(),
+                position(u64::from(i), RecordKind::Put),
+            );
+        }
+
+        assert_eq!(keydir.len(), 1000 - 334 + 100);
+        assert_eq!(keydir.entries.len(), 1000);
+        for i in 0..1100u32 {
+            let found = keydir.get(format!("key-{}", i).as_bytes());
+            assert_eq!(found.is_some(), i >= 1000 || i % 3 != 0);
+        }
+        assert_eq!(keydir.iter().count(), keydir.len());
+
+        for i in 0..1100u32 {
+            keydir.remove(format!("key-{}", i).as_bytes());
+        }
+        assert_eq!(keydir.len(), 0);
+        assert!(keydir.arena.len() < 1000);
+    }
+}
@@ -1,18 +1,142 @@
-use failure::Error;
+use batch::WriteBatch;
+use error::Error;
+use keydir::{KeyDir, KeyDirFactory, KeyDirKind, KeyDirStats};
 use keys_iterator::StoreKeys;
+use merge::MergeHandle;
+use range_iterator::StoreRange;
+use segment::{current_timestamp, ValueRef};
 use serde_yaml;
+use stats::SegmentStats;
 use std;
 use std::borrow::Borrow;
 use std::fs::File;
-use std::hash::Hash;
+use std::ops::{Bound, RangeBounds};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 use store::Store;
+use transaction::Transaction;
 
 pub type Key = Vec<u8>;
 pub type Value = Vec<u8>;
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// When writes are fsynced to disk.
+#[derive(Copy, Clone, Serialize, Deserialize, Debug, PartialEq, Default)]
+pub enum SyncPolicy {
+    /// Leave flushing to the operating system.
+    #[default]
+    Never,
+    /// Sync before every write returns.
+    EveryWrite,
+    /// Sync after every n writes.
+    EveryN(u64),
+    /// Sync from a background thread at this interval.
+    Interval(Duration),
+}
+
+/// Thresholds for merging in the background.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct AutoMerge {
+    /// A segment qualifies for merging once this fraction of it is dead.
+    pub fragmentation: f64,
+    /// A segment also qualifies once it holds this many dead bytes.
+    pub dead_bytes: u64,
+    /// Merge only when at least this many segments qualify.
+    pub min_files: usize,
+    /// UTC hours `(start, end)` during which merges may start, wrapping
+    /// past midnight when `start > end`. `None` allows any time.
+    pub window: Option<(u8, u8)>,
+    /// How often the thresholds are checked.
+    pub check_interval: Duration,
+    /// How much one run merges, starting from the oldest segment that
+    /// qualifies.
+    #[serde(default)]
+    pub limits: MergeLimits,
+}
+
+impl Default for AutoMerge {
+    fn default() -> Self {
+        AutoMerge {
+            fragmentation: 0.5,
+            dead_bytes: 512 * 1024 * 1024,
+            min_files: 1,
+            window: None,
+            check_interval: Duration::from_secs(60),
+            limits: MergeLimits::default(),
+        }
+    }
+}
+
+impl AutoMerge {
+    /// Whether `now`, in milliseconds since the unix epoch, falls inside
+    /// the merge window.
+    pub fn in_window(&self, now: u64) -> bool {
+        let hour = (now / 3_600_000 % 24) as u8;
+        match self.window {
+            None => true,
+            Some((start, end)) if start < end => start <= hour && hour < end,
+            Some((start, end)) => hour >= start || hour < end,
+        }
+    }
+
+    fn validate(&self) -> Result<()> {
+        if !(self.fragmentation > 0.0 && self.fragmentation <= 1.0) {
+            return Err(Error::InvalidConfig(
+                "auto_merge.fragmentation must be in (0, 1]".to_string(),
+            ));
+        }
+        if self.dead_bytes == 0 || self.min_files == 0 {
+            return Err(Error::InvalidConfig(
+                "auto_merge.dead_bytes and auto_merge.min_files must be positive".to_string(),
+            ));
+        }
+        if let Some((start, end)) = self.window {
+            if start >= 24 || end >= 24 || start == end {
+                return Err(Error::InvalidConfig(
+                    "auto_merge.window must be two different hours below 24".to_string(),
+                ));
+            }
+        }
+        if self.check_interval == Duration::from_secs(0) {
+            return Err(Error::InvalidConfig(
+                "auto_merge.check_interval must be positive".to_string(),
+            ));
+        }
+        self.limits.validate()
+    }
+}
+
+/// How much of the store one step of an incremental merge takes on. A step
+/// always takes at least one segment, however large.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct MergeLimits {
+    pub max_segments: usize,
+    /// Total size of the segments merged in one step.
+    pub max_bytes: u64,
+}
+
+impl Default for MergeLimits {
+    fn default() -> Self {
+        MergeLimits {
+            max_segments: 4,
+            max_bytes: 256 * 1024 * 1024,
+        }
+    }
+}
+
+impl MergeLimits {
+    fn validate(&self) -> Result<()> {
+        if self.max_segments == 0 || self.max_bytes == 0 {
+            return Err(Error::InvalidConfig(
+                "merge limits must be positive".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
 #[derive(Builder, Clone)]
 #[builder(default)]
 #[derive(Serialize, Deserialize, Debug)]
@@ -21,6 +145,40 @@ pub struct Config {
     pub max_size_per_segment: u64,
     pub max_file_id: u64,
     pub min_merge_file_id: u64,
+    #[serde(default)]
+    pub sync_policy: SyncPolicy,
+    /// Merge fragmented segments from a background thread. `None` leaves
+    /// merging to `Bitcask::merge`.
+    #[serde(default)]
+    pub auto_merge: Option<AutoMerge>,
+    /// Bytes per second merges may read and write, counted together.
+    /// `None` lets them run at full speed.
+    #[serde(default)]
+    pub merge_rate_limit: Option<u64>,
+    /// Read segments that are no longer written to through memory mappings
+    /// rather than a read syscall per value.
+    #[serde(default)]
+    pub mmap_reads: bool,
+    /// How the keydir holds keys in memory.
+    #[serde(default)]
+    pub keydir: KeyDirKind,
+    /// Builds the keydir in place of `keydir`, for implementations of
+    /// `KeyDir` outside this crate. Cannot be set from a config file.
+    #[serde(skip)]
+    pub custom_keydir: Option<KeyDirFactory>,
+    /// Write a keydir snapshot from a background thread at this interval,
+    /// so opening the store only reads the segments written since. `None`
+    /// leaves snapshots to `Bitcask::write_snapshot`.
+    #[serde(default)]
+    pub snapshot_interval: Option<Duration>,
+    /// Threads that read segments and hints in parallel when the store is
+    /// opened. Defaults to one per core.
+    #[serde(default = "default_load_threads")]
+    pub load_threads: usize,
+}
+
+fn default_load_threads() -> usize {
+    thread::available_parallelism().map_or(1, |n| n.get())
 }
 
 impl Default for Config {
@@ -30,15 +188,89 @@ impl Default for Config {
             max_size_per_segment: 100_000_000,
             max_file_id: 1_000_000_000,
             min_merge_file_id: 100_000_000_000,
+            sync_policy: SyncPolicy::default(),
+            auto_merge: None,
+            merge_rate_limit: None,
+            mmap_reads: false,
+            keydir: KeyDirKind::default(),
+            custom_keydir: None,
+            snapshot_interval: None,
+            load_threads: default_load_threads(),
         }
     }
 }
 
 impl Config {
-    pub fn new<T: AsRef<Path>>(config_path: T) -> Self {
-        let mut file = File::open(config_path).expect("open config path");
-        let config: Config = serde_yaml::from_reader(&mut file).expect("deserialize config file");
-        config
+    pub fn new<T: AsRef<Path>>(config_path: T) -> Result<Self> {
+        let mut file = File::open(config_path)?;
+        let config: Config =
+            serde_yaml::from_reader(&mut file).map_err(|e| Error::InvalidConfig(e.to_string()))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// An empty keydir of the configured kind.
+    pub(crate) fn build_keydir(&self) -> Box<dyn KeyDir> {
+        match self.custom_keydir {
+            Some(ref factory) => factory.build(),
+            None => self.keydir.build(),
+        }
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.max_size_per_segment == 0 {
+            return Err(Error::InvalidConfig(
+                "max_size_per_segment must be positive".to_string(),
+            ));
+        }
+        // Merge outputs are numbered from `min_merge_file_id` and must not
+        // collide with regular segments.
+        if self.max_file_id >= self.min_merge_file_id {
+            return Err(Error::InvalidConfig(
+                "max_file_id must be below min_merge_file_id".to_string(),
+            ));
+        }
+        match self.sync_policy {
+            SyncPolicy::EveryN(0) => {
+                return Err(Error::InvalidConfig(
+                    "sync_policy EveryN must be positive".to_string(),
+                ))
+            }
+            SyncPolicy::Interval(interval) if interval == Duration::from_secs(0) => {
+                return Err(Error::InvalidConfig(
+                    "sync_policy Interval must be positive".to_string(),
+                ))
+            }
+            _ => {}
+        }
+        if let Some(ref auto_merge) = self.auto_merge {
+            auto_merge.validate()?;
+        }
+        if self.merge_rate_limit == Some(0) {
+            return Err(Error::InvalidConfig(
+                "merge_rate_limit must be positive".to_string(),
+            ));
+        }
+        if self.load_threads == 0 {
+            return Err(Error::InvalidConfig(
+                "load_threads must be positive".to_string(),
+            ));
+        }
+        if self.snapshot_interval == Some(Duration::from_secs(0)) {
+            return Err(Error::InvalidConfig(
+                "snapshot_interval must be positive".to_string(),
+            ));
+        }
+        if self.keydir == KeyDirKind::Compact {
+            let max = u64::from(u32::MAX);
+            if self.max_file_id > max || self.max_size_per_segment > max {
+                return Err(Error::InvalidConfig(
+                    "a compact keydir needs max_file_id and max_size_per_segment to fit in 32 bits"
+                        .to_string(),
+                ));
+            }
+        }
+        Ok(())
     }
 }
 
@@ -47,62 +279,294 @@ pub struct Bitcask {
     store: Arc<Store>,
 }
 
+/// Sync `store` every `interval` until the last handle to it is dropped.
+fn spawn_flusher(store: &Arc<Store>, interval: Duration) {
+    let store = Arc::downgrade(store);
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        match store.upgrade() {
+            Some(store) => {
+                if let Err(e) = store.sync() {
+                    error!(target: "bitcask::core::flusher", "sync failed: {}", e);
+                }
+            }
+            None => break,
+        }
+    });
+}
+
+/// Write a keydir snapshot of `store` every `interval` until the last handle
+/// to it is dropped.
+fn spawn_snapshotter(store: &Arc<Store>, interval: Duration) {
+    let store = Arc::downgrade(store);
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        match store.upgrade() {
+            Some(store) => {
+                if let Err(e) = store.write_snapshot() {
+                    error!(target: "bitcask::core::snapshotter", "snapshot failed: {}", e);
+                }
+            }
+            None => break,
+        }
+    });
+}
+
+/// Merge `store` whenever `policy` calls for it, until the last handle to
+/// it is dropped.
+fn spawn_merger(store: &Arc<Store>, policy: AutoMerge, rate_limit: Option<u64>) {
+    let store = Arc::downgrade(store);
+    thread::spawn(move || loop {
+        thread::sleep(policy.check_interval);
+        let store = match store.upgrade() {
+            Some(store) => store,
+            None => break,
+        };
+        if !policy.in_window(current_timestamp()) {
+            continue;
+        }
+        if let Some(since) = store.merge_candidate(&policy) {
+            debug!(target: "bitcask::core::merger", "merge since {}", since);
+            if let Err(e) = store.merge_step(since, u64::MAX, &policy.limits, rate_limit) {
+                error!(target: "bitcask::core::merger", "merge failed: {}", e);
+            }
+        }
+    });
+}
+
 impl Bitcask {
-    pub fn new(config: Config) -> Self {
+    pub fn new(config: Config) -> Result<Self> {
+        config.validate()?;
         let arc_config = Arc::new(config);
+        let store = Store::new(arc_config.clone())?;
+        Ok(Self::start(Arc::new(store), arc_config))
+    }
 
-        Bitcask {
-            store: Arc::new(Store::new(arc_config.clone())),
-            config: arc_config,
-        }
+    pub fn open(config: Config) -> Result<Self> {
+        config.validate()?;
+        let arc_config = Arc::new(config);
+        let store = Store::open(arc_config.clone())?;
+        Ok(Self::start(Arc::new(store), arc_config))
     }
 
-    pub fn open(config: Config) -> Self {
+    /// Open an existing store for reading alongside the process that writes
+    /// it. Writes and merges fail with `Error::ReadOnly`.
+    pub fn open_read_only(config: Config) -> Result<Self> {
+        config.validate()?;
         let arc_config = Arc::new(config);
-        Bitcask {
-            store: Arc::new(Store::open(arc_config.clone())),
-            config: arc_config,
+        let store = Store::open_read_only(arc_config.clone())?;
+        Ok(Self::start(Arc::new(store), arc_config))
+    }
+
+    /// Pick up what the writer has written since a read-only store was
+    /// opened or last refreshed.
+    pub fn refresh(&self) -> Result<()> {
+        self.store.refresh()
+    }
+
+    fn start(store: Arc<Store>, config: Arc<Config>) -> Self {
+        if let SyncPolicy::Interval(interval) = config.sync_policy {
+            spawn_flusher(&store, interval);
+        }
+        if let Some(ref policy) = config.auto_merge {
+            if !store.is_read_only() {
+                spawn_merger(&store, policy.clone(), config.merge_rate_limit);
+            }
+        }
+        if let Some(interval) = config.snapshot_interval {
+            if !store.is_read_only() {
+                spawn_snapshotter(&store, interval);
+            }
         }
+        Bitcask { store, config }
     }
 
     pub fn get<Q>(&self, key: &Q) -> Result<Option<Value>>
     where
         Key: Borrow<Q>,
-        Q: Eq + Hash + ?Sized,
+        Q: AsRef<[u8]> + ?Sized,
     {
         self.store.get(key)
     }
 
+    /// Like `get`, but values of memory-mapped segments are handed out
+    /// without copying them. See `Config::mmap_reads`.
+    pub fn get_bytes<Q>(&self, key: &Q) -> Result<Option<ValueRef>>
+    where
+        Key: Borrow<Q>,
+        Q: AsRef<[u8]> + ?Sized,
+    {
+        self.store.get_bytes(key)
+    }
+
     pub fn set(&mut self, key: Key, value: Value) -> Result<()> {
         self.store.insert(key, value)
     }
 
+    /// Set `key` to `value`, expiring it once `ttl` has passed.
+    pub fn set_with_ttl(&mut self, key: Key, value: Value, ttl: Duration) -> Result<()> {
+        self.store.insert_with_ttl(key, value, ttl)
+    }
+
     pub fn delete(&mut self, key: Key) -> Result<()> {
         self.store.delete(key)
     }
 
+    /// Apply all writes in `batch` atomically.
+    pub fn write(&mut self, batch: WriteBatch) -> Result<()> {
+        self.store.write(batch)
+    }
+
+    /// Set `key` to `new`, or delete it if `new` is `None`, only if its
+    /// current value equals `expected`. Returns whether the swap happened.
+    pub fn compare_and_swap(
+        &mut self,
+        key: Key,
+        expected: Option<&[u8]>,
+        new: Option<Value>,
+    ) -> Result<bool> {
+        self.store.compare_and_swap(key, expected, new)
+    }
+
+    pub fn transaction(&self) -> Transaction {
+        Transaction::new(self.store.clone())
+    }
+
+    /// Give an existing key a new time to live. Returns `false` if the key does
+    /// not exist.
+    pub fn expire(&mut self, key: Key, ttl: Duration) -> Result<bool> {
+        self.store.expire(key, ttl)
+    }
+
+    /// Remaining time to live of `key`, or `None` if the key does not exist or
+    /// never expires.
+    pub fn ttl<Q>(&self, key: &Q) -> Option<Duration>
+    where
+        Key: Borrow<Q>,
+        Q: AsRef<[u8]> + ?Sized,
+    {
+        self.store.ttl(key)
+    }
+
     pub fn exists<Q>(&self, key: &Q) -> Result<bool>
     where
         Key: Borrow<Q>,
-        Q: Eq + Hash + ?Sized,
+        Q: AsRef<[u8]> + ?Sized,
     {
         self.store.exists(key)
     }
 
+    /// Write time of `key` in milliseconds since the unix epoch, or `None`
+    /// if the key does not exist.
+    pub fn timestamp<Q>(&self, key: &Q) -> Option<u64>
+    where
+        Key: Borrow<Q>,
+        Q: AsRef<[u8]> + ?Sized,
+    {
+        self.store.timestamp(key)
+    }
+
+    /// Live and dead record counts of every segment, ordered by file id.
+    pub fn segment_stats(&self) -> Vec<SegmentStats> {
+        self.store.segment_stats()
+    }
+
+    /// Number of keys in the keydir and the memory it takes.
+    pub fn keydir_stats(&self) -> KeyDirStats {
+        self.store.keydir_stats()
+    }
+
     pub fn merge(&mut self, since: Option<u64>) -> Result<()> {
-        let file_ids = if let Some(file_id) = since {
-            self.store.prepare_merging_since(file_id)
-        } else {
-            self.store.prepare_full_merging()
-        };
-        debug!(target: "core::merge", "file_ids: {:?}", file_ids);
-        let ret = self.store.merge(&file_ids)?;
-        self.store.finish_merging(ret)
+        self.store.merge_since(since, self.config.merge_rate_limit)
+    }
+
+    /// Like `merge`, but throttled to `rate_limit` bytes per second instead
+    /// of `Config::merge_rate_limit`. `None` runs it at full speed.
+    pub fn merge_with_rate_limit(
+        &mut self,
+        since: Option<u64>,
+        rate_limit: Option<u64>,
+    ) -> Result<()> {
+        if rate_limit == Some(0) {
+            return Err(Error::InvalidConfig(
+                "merge rate limit must be positive".to_string(),
+            ));
+        }
+        self.store.merge_since(since, rate_limit)
+    }
+
+    /// Merge the older segments, or those from file id `since` on, in steps
+    /// bounded by `limits` from a background thread. Each step holds the
+    /// store's locks only for its own segments, so reads and writes carry on
+    /// in between.
+    pub fn merge_incremental(
+        &self,
+        since: Option<u64>,
+        limits: MergeLimits,
+    ) -> Result<MergeHandle> {
+        limits.validate()?;
+        if self.store.is_read_only() {
+            return Err(Error::ReadOnly);
+        }
+        let last = self.store.older_file_ids().last().cloned().unwrap_or(0);
+        Ok(MergeHandle::spawn(
+            &self.store,
+            since.unwrap_or(0),
+            last,
+            limits,
+            self.config.merge_rate_limit,
+        ))
+    }
+
+    /// Save the keydir so the next open only has to read the records
+    /// written after now. Merges remove the snapshot, since it points into
+    /// the segments they replace.
+    pub fn write_snapshot(&self) -> Result<()> {
+        self.store.write_snapshot()
+    }
+
+    /// Flush and fsync everything written so far.
+    pub fn sync(&self) -> Result<()> {
+        self.store.sync()
     }
 
     pub fn keys(&self) -> StoreKeys {
         self.store.keys()
     }
+
+    /// Live keys within `range` and their values, in byte order; iterate it
+    /// with `.iter().rev()` for descending order. Needs `KeyDirKind::Ordered`.
+    pub fn range<R: RangeBounds<Key>>(&self, range: R) -> Result<StoreRange<'_>> {
+        self.store
+            .range((cloned(range.start_bound()), cloned(range.end_bound())))
+    }
+
+    /// Live keys starting with `prefix` and their values, in byte order.
+    /// Needs `KeyDirKind::Ordered`.
+    pub fn scan_prefix(&self, prefix: &[u8]) -> Result<StoreRange<'_>> {
+        self.store
+            .range((Bound::Included(prefix.to_vec()), prefix_end(prefix)))
+    }
+}
+
+fn cloned(bound: Bound<&Key>) -> Bound<Key> {
+    match bound {
+        Bound::Included(key) => Bound::Included(key.clone()),
+        Bound::Excluded(key) => Bound::Excluded(key.clone()),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+/// The first key past every key starting with `prefix`.
+fn prefix_end(prefix: &[u8]) -> Bound<Key> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < 0xff {
+            end.push(last + 1);
+            return Bound::Excluded(end);
+        }
+    }
+    Bound::Unbounded
 }
 
 impl Clone for Bitcask {
@@ -0,0 +1,75 @@
+#![allow(unknown_lints, non_local_definitions)]
+
+use core::Key;
+use std::io;
+use std::path::PathBuf;
+
+#[derive(Debug, Fail)]
+pub enum Error {
+    #[fail(display = "io error: {}", _0)]
+    Io(#[cause] io::Error),
+    /// The record at `offset` is cut short or fails its checksum.
+    #[fail(
+        display = "corrupted record in segment {} at offset {}",
+        file_id, offset
+    )]
+    Corruption { file_id: u64, offset: u64 },
+    #[fail(display = "invalid config: {}", _0)]
+    InvalidConfig(String),
+    /// Another process has the store directory open.
+    #[fail(display = "store directory {:?} is locked", _0)]
+    Locked(PathBuf),
+    /// A write or merge was attempted on a store opened read-only.
+    #[fail(display = "store is opened read-only")]
+    ReadOnly,
+    /// A write needed a new segment but every file id below `max_file_id`
+    /// is taken.
+    #[fail(display = "no segment file id left below {}", max_file_id)]
+    OutOfFileIds { max_file_id: u64 },
+    /// A read-only store kept finding a merge swapping files while it
+    /// loaded.
+    #[fail(display = "a merge kept swapping segments while loading")]
+    MergeInProgress,
+    /// A key or value was longer than `max_len`, the most the configured
+    /// keydir can point at.
+    #[fail(display = "key or value longer than {} bytes", max_len)]
+    TooLarge { max_len: u64 },
+    /// A range scan was attempted on a store without an ordered keydir.
+    #[fail(display = "range scans need KeyDirKind::Ordered")]
+    Unordered,
+    /// Returned by `Transaction::commit` when a key the transaction read was
+    /// written by someone else before the commit.
+    #[fail(display = "transaction conflict on key {:?}", key)]
+    Conflict { key: Key },
+}
+
+impl Error {
+    /// A copy of the error for each of several callers it failed. Io errors
+    /// keep their kind and message but lose their source.
+    pub(crate) fn duplicate(&self) -> Error {
+        match *self {
+            Error::Io(ref e) => Error::Io(io::Error::new(e.kind(), e.to_string())),
+            Error::Corruption { file_id, offset } => Error::Corruption { file_id, offset },
+            Error::InvalidConfig(ref message) => Error::InvalidConfig(message.clone()),
+            Error::Locked(ref path) => Error::Locked(path.clone()),
+            Error::ReadOnly => Error::ReadOnly,
+            Error::OutOfFileIds { max_file_id } => Error::OutOfFileIds { max_file_id },
+            Error::MergeInProgress => Error::MergeInProgress,
+            Error::TooLarge { max_len } => Error::TooLarge { max_len },
+            Error::Unordered => Error::Unordered,
+            Error::Conflict { ref key } => Error::Conflict { key: key.clone() },
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+/// Malformed bytes found while decoding a file. Segment readers turn this
+/// into `Error::Corruption` once the file and offset are known.
+pub fn invalid_data<M: Into<String>>(message: M) -> Error {
+    Error::Io(io::Error::new(io::ErrorKind::InvalidData, message.into()))
+}
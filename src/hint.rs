@@ -1,21 +1,33 @@
 use core::{Key, Result};
+use error::invalid_data;
 use integer_encoding::{VarInt, VarIntReader, VarIntWriter};
 use io_at::Cursor;
-use segment::Offset;
+use segment::{Offset, RecordKind};
 use std::fs::{create_dir_all, remove_file, File, OpenOptions};
 use std::io::{Read, Seek, SeekFrom, Write};
 use std::path::PathBuf;
 use store::Position;
 
+const MAGIC: &[u8; 4] = b"BCHT";
+const HEADER_SIZE: u64 = 5;
+const VERSION: u8 = 2;
+
 pub struct HintEntry {
     pub key: Key,
-    pub key_size: u64,
+    pub size: u64,
     pub position: Position,
 }
 
-fn read_from_cursor(file: &mut Cursor<&File>) -> Result<HintEntry> {
+/// Read the entry at the cursor, of at most `limit` bytes.
+fn read_from_cursor(file: &mut Cursor<&File>, limit: u64) -> Result<HintEntry> {
     let key_size = file.read_varint::<u64>()?;
     debug!(target: "bitcask::hint::read_from_cursor", "get key size {}", key_size);
+    if key_size > limit {
+        return Err(invalid_data(format!(
+            "key size {} exceeds the {} bytes left in hint",
+            key_size, limit
+        )));
+    }
     let mut key_buf = vec![0; key_size as usize];
     file.read_exact(&mut key_buf)?;
     debug!(target: "bitcask::hint::read_from_cursor", "get key buf {:?}", key_buf);
@@ -23,10 +35,40 @@ fn read_from_cursor(file: &mut Cursor<&File>) -> Result<HintEntry> {
     debug!(target: "bitcask::hint::read_from_cursor", "get file id {}", file_id);
     let offset = file.read_varint::<u64>()?;
     debug!(target: "bitcask::hint::read_from_cursor", "get file pos {}", offset);
+    let value_pos = file.read_varint::<u64>()?;
+    debug!(target: "bitcask::hint::read_from_cursor", "get value pos {}", value_pos);
+    let value_size = file.read_varint::<u64>()?;
+    debug!(target: "bitcask::hint::read_from_cursor", "get value size {}", value_size);
+    let tstamp = file.read_varint::<u64>()?;
+    debug!(target: "bitcask::hint::read_from_cursor", "get tstamp {}", tstamp);
+    let expiry = file.read_varint::<u64>()?;
+    debug!(target: "bitcask::hint::read_from_cursor", "get expiry {}", expiry);
+    let mut kind_buf = [0; 1];
+    file.read_exact(&mut kind_buf)?;
+    let kind = RecordKind::from_byte(kind_buf[0])?;
+    debug!(target: "bitcask::hint::read_from_cursor", "get kind {:?}", kind);
+
+    let size = key_size.required_space() as u64
+        + key_size
+        + file_id.required_space() as u64
+        + offset.required_space() as u64
+        + value_pos.required_space() as u64
+        + value_size.required_space() as u64
+        + tstamp.required_space() as u64
+        + expiry.required_space() as u64
+        + 1;
     Ok(HintEntry {
         key: key_buf,
-        key_size,
-        position: Position { file_id, offset },
+        size,
+        position: Position {
+            file_id,
+            offset,
+            value_pos,
+            value_size,
+            tstamp,
+            expiry,
+            kind,
+        },
     })
 }
 
@@ -42,30 +84,41 @@ impl Hint {
         path.join(format!("{}.hint", file_id))
     }
 
-    pub fn new(file_id: u64, path: &PathBuf) -> Self {
-        create_dir_all(&path).expect("create dir");
+    pub fn new(file_id: u64, path: &PathBuf) -> Result<Self> {
+        create_dir_all(&path)?;
         let file_path = Self::get_path(file_id, path);
-        let file = OpenOptions::new()
+        let mut file = OpenOptions::new()
             .create(true)
             .write(true)
             .truncate(true)
             .read(true)
-            .open(&file_path)
-            .expect("open segment file");
+            .open(&file_path)?;
+        file.write_all(MAGIC)?;
+        file.write_all(&[VERSION])?;
 
         debug!(target: "bitcask::hint::new", "new hint file {:?}", &file_path);
-        Hint {
+        Ok(Hint {
             file_id,
             file_path,
             file: Some(file),
-            size: 0,
-        }
+            size: HEADER_SIZE,
+        })
     }
 
+    /// Fails for hints written in an older format; those are rebuilt from
+    /// their segment.
     pub fn open(file_id: u64, path: &PathBuf) -> Result<Self> {
         let file_path = Self::get_path(file_id, path);
         let mut file = OpenOptions::new().read(true).open(&file_path)?;
 
+        let mut header = [0; HEADER_SIZE as usize];
+        file.read_exact(&mut header)?;
+        if &header[..MAGIC.len()] != MAGIC || header[MAGIC.len()] != VERSION {
+            return Err(invalid_data(format!(
+                "unsupported hint file {:?}",
+                file_path
+            )));
+        }
         let size = file.seek(SeekFrom::End(0))?;
         Ok(Hint {
             file_id,
@@ -77,7 +130,9 @@ impl Hint {
 
     pub fn get(&self, offset: Offset) -> Result<Option<Position>> {
         let mut file = Cursor::new(self.file.as_ref().expect("get file"), offset);
-        Ok(Some(read_from_cursor(&mut file)?.position))
+        Ok(Some(
+            read_from_cursor(&mut file, self.size - offset)?.position,
+        ))
     }
 
     pub fn insert(&mut self, key: &Key, position: Position) -> Result<Offset> {
@@ -92,14 +147,34 @@ impl Hint {
         let file_id_length = file.write_varint(position.file_id)?;
         debug!(target: "bitcask::hint::insert", "insert file offset {:?}", position.offset);
         let file_offset_length = file.write_varint(position.offset)?;
+        debug!(target: "bitcask::hint::insert", "insert value pos {:?}", position.value_pos);
+        let value_pos_length = file.write_varint(position.value_pos)?;
+        debug!(target: "bitcask::hint::insert", "insert value size {:?}", position.value_size);
+        let value_size_length = file.write_varint(position.value_size)?;
+        debug!(target: "bitcask::hint::insert", "insert tstamp {:?}", position.tstamp);
+        let tstamp_length = file.write_varint(position.tstamp)?;
+        debug!(target: "bitcask::hint::insert", "insert expiry {:?}", position.expiry);
+        let expiry_length = file.write_varint(position.expiry)?;
+        debug!(target: "bitcask::hint::insert", "insert kind {:?}", position.kind);
+        file.write_all(&[position.kind.to_byte()])?;
 
         self.size += key_size_length as u64
             + file_id_length as u64
             + file_offset_length as u64
+            + value_pos_length as u64
+            + value_size_length as u64
+            + tstamp_length as u64
+            + expiry_length as u64
+            + 1
             + key_buf.len() as u64;
         Ok(offset)
     }
 
+    pub fn sync(&self) -> Result<()> {
+        self.file.as_ref().expect("get file").sync_data()?;
+        Ok(())
+    }
+
     pub fn destroy(&mut self) -> Result<()> {
         self.file = None;
         remove_file(&self.file_path)?;
@@ -123,7 +198,7 @@ impl<'a> IntoIterator for &'a Hint {
     fn into_iter(self) -> <Self as IntoIterator>::IntoIter {
         HintIterator {
             hint: self,
-            offset: 0,
+            offset: HEADER_SIZE,
         }
     }
 }
@@ -143,12 +218,15 @@ impl<'a> Iterator for HintIterator<'a> {
                self.hint.size
         );
         let mut file = Cursor::new(self.hint.file.as_ref().expect("get file"), self.offset);
-        let hint_entry = read_from_cursor(&mut file).expect("read from cursor");
-
-        self.offset += hint_entry.key_size.required_space() as u64
-            + hint_entry.position.file_id.required_space() as u64
-            + hint_entry.position.offset.required_space() as u64
-            + hint_entry.key_size;
+        let hint_entry = match read_from_cursor(&mut file, self.hint.size - self.offset) {
+            Ok(hint_entry) => hint_entry,
+            Err(e) => {
+                self.offset = self.hint.size;
+                return Some(Err(e));
+            }
+        };
+
+        self.offset += hint_entry.size;
 
         Some(Ok(hint_entry))
     }
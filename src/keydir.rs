@@ -0,0 +1,231 @@
+use compact_keydir::CompactKeyDir;
+use core::Key;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::mem;
+use std::ops::Bound;
+use std::sync::Arc;
+use store::Position;
+
+/// Which `KeyDir` implementation a store keeps its keys in.
+#[derive(Copy, Clone, Serialize, Deserialize, Debug, PartialEq, Default)]
+pub enum KeyDirKind {
+    /// A `HashMap` of owned keys.
+    #[default]
+    Hashed,
+    /// A `BTreeMap` of owned keys, kept in byte order so `Bitcask::range`
+    /// and `Bitcask::scan_prefix` can scan them, at some cost to lookups.
+    Ordered,
+    /// Keys and positions packed into as little memory as possible, for
+    /// stores with hundreds of millions of keys. Needs file ids and segment
+    /// sizes that fit in 32 bits.
+    Compact,
+}
+
+impl KeyDirKind {
+    pub fn build(self) -> Box<dyn KeyDir> {
+        match self {
+            KeyDirKind::Hashed => Box::new(HashMap::with_capacity(100)),
+            KeyDirKind::Ordered => Box::new(BTreeMap::new()),
+            KeyDirKind::Compact => Box::new(CompactKeyDir::default()),
+        }
+    }
+}
+
+/// Builds the keydir of a store from a `KeyDir` implementation outside this
+/// crate. Set as `Config::custom_keydir`.
+#[derive(Clone)]
+pub struct KeyDirFactory(Arc<dyn Fn() -> Box<dyn KeyDir> + Send + Sync>);
+
+impl KeyDirFactory {
+    pub fn new<F>(build: F) -> Self
+    where
+        F: Fn() -> Box<dyn KeyDir> + Send + Sync + 'static,
+    {
+        KeyDirFactory(Arc::new(build))
+    }
+
+    pub fn build(&self) -> Box<dyn KeyDir> {
+        (self.0)()
+    }
+}
+
+impl fmt::Debug for KeyDirFactory {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("KeyDirFactory")
+    }
+}
+
+/// Keydir entries in key order, from either end.
+pub type OrderedEntries<'a> = Box<dyn DoubleEndedIterator<Item = (&'a [u8], Position)> + 'a>;
+
+/// Position of the newest record of each key, whichever segment it is in.
+///
+/// Implement it to keep keys somewhere other than the built-in keydirs and
+/// plug it in through `KeyDirFactory`. An ordered implementation returns
+/// `Some` from `range` whenever `is_ordered` is true.
+pub trait KeyDir: Send + Sync {
+    fn get(&self, key: &[u8]) -> Option<Position>;
+
+    /// Returns the position `key` had before, if any.
+    fn insert(&mut self, key: Key, pos: Position) -> Option<Position>;
+
+    fn remove(&mut self, key: &[u8]) -> Option<Position>;
+
+    fn clear(&mut self);
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (&'a [u8], Position)> + 'a>;
+
+    /// Whether `range` is supported.
+    fn is_ordered(&self) -> bool {
+        false
+    }
+
+    /// Entries with keys within `bounds`, in key order. `None` unless the
+    /// keydir is ordered.
+    fn range<'a>(&'a self, _bounds: (Bound<Key>, Bound<Key>)) -> Option<OrderedEntries<'a>> {
+        None
+    }
+
+    /// Estimated heap bytes held, keys included.
+    fn memory_usage(&self) -> usize;
+
+    fn stats(&self) -> KeyDirStats {
+        KeyDirStats {
+            keys: self.len() as u64,
+            bytes: self.memory_usage() as u64,
+        }
+    }
+}
+
+/// Size of the keydir, as returned by `Bitcask::keydir_stats`.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct KeyDirStats {
+    pub keys: u64,
+    /// Estimated heap bytes held by the keydir, keys included.
+    pub bytes: u64,
+}
+
+impl KeyDirStats {
+    /// Average bytes per key, for sizing memory against a key count.
+    pub fn bytes_per_key(&self) -> f64 {
+        if self.keys == 0 {
+            return 0.0;
+        }
+        self.bytes as f64 / self.keys as f64
+    }
+}
+
+/// Bytes of one owned key and its position, besides the key's allocation.
+fn entry_size() -> usize {
+    mem::size_of::<Key>() + mem::size_of::<Position>()
+}
+
+impl KeyDir for HashMap<Key, Position> {
+    fn get(&self, key: &[u8]) -> Option<Position> {
+        HashMap::get(self, key).cloned()
+    }
+
+    fn insert(&mut self, key: Key, pos: Position) -> Option<Position> {
+        HashMap::insert(self, key, pos)
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Option<Position> {
+        HashMap::remove(self, key)
+    }
+
+    fn clear(&mut self) {
+        HashMap::clear(self)
+    }
+
+    fn len(&self) -> usize {
+        HashMap::len(self)
+    }
+
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (&'a [u8], Position)> + 'a> {
+        Box::new(HashMap::iter(self).map(|(k, pos)| (k.as_slice(), *pos)))
+    }
+
+    /// One control byte per bucket besides the entry itself. Walks every
+    /// key.
+    fn memory_usage(&self) -> usize {
+        self.capacity() * (entry_size() + 1) + self.keys().map(Vec::capacity).sum::<usize>()
+    }
+}
+
+impl KeyDir for BTreeMap<Key, Position> {
+    fn get(&self, key: &[u8]) -> Option<Position> {
+        BTreeMap::get(self, key).cloned()
+    }
+
+    fn insert(&mut self, key: Key, pos: Position) -> Option<Position> {
+        BTreeMap::insert(self, key, pos)
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Option<Position> {
+        BTreeMap::remove(self, key)
+    }
+
+    fn clear(&mut self) {
+        BTreeMap::clear(self)
+    }
+
+    fn len(&self) -> usize {
+        BTreeMap::len(self)
+    }
+
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (&'a [u8], Position)> + 'a> {
+        Box::new(BTreeMap::iter(self).map(|(k, pos)| (k.as_slice(), *pos)))
+    }
+
+    fn is_ordered(&self) -> bool {
+        true
+    }
+
+    fn range<'a>(&'a self, bounds: (Bound<Key>, Bound<Key>)) -> Option<OrderedEntries<'a>> {
+        Some(Box::new(
+            BTreeMap::range(self, bounds).map(|(k, pos)| (k.as_slice(), *pos)),
+        ))
+    }
+
+    /// Nodes run between half and completely full. Walks every key.
+    fn memory_usage(&self) -> usize {
+        self.len() * entry_size() * 3 / 2 + self.keys().map(Vec::capacity).sum::<usize>()
+    }
+}
+
+impl KeyDir for CompactKeyDir {
+    fn get(&self, key: &[u8]) -> Option<Position> {
+        CompactKeyDir::get(self, key)
+    }
+
+    fn insert(&mut self, key: Key, pos: Position) -> Option<Position> {
+        CompactKeyDir::insert(self, &key, pos)
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Option<Position> {
+        CompactKeyDir::remove(self, key)
+    }
+
+    fn clear(&mut self) {
+        *self = CompactKeyDir::default();
+    }
+
+    fn len(&self) -> usize {
+        CompactKeyDir::len(self)
+    }
+
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (&'a [u8], Position)> + 'a> {
+        Box::new(CompactKeyDir::iter(self))
+    }
+
+    fn memory_usage(&self) -> usize {
+        CompactKeyDir::memory_usage(self)
+    }
+}
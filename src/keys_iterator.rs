@@ -1,51 +1,44 @@
-use core::Key;
-use std::collections::HashSet;
+use keydir::KeyDir;
+use segment::current_timestamp;
 use std::sync::RwLockReadGuard;
-use store::{ActiveData, OlderData};
+use store::Position;
 
 pub struct StoreKeys<'a> {
-    pub active_data_guard: RwLockReadGuard<'a, ActiveData>,
-    pub older_data_guard: RwLockReadGuard<'a, OlderData>,
+    pub keydir_guard: RwLockReadGuard<'a, Box<dyn KeyDir>>,
 }
 
 impl<'a> IntoIterator for &'a StoreKeys<'a> {
-    type Item = &'a Key;
+    type Item = &'a [u8];
     type IntoIter = StoreKeysIter<'a>;
 
     fn into_iter(self) -> <Self as IntoIterator>::IntoIter {
-        StoreKeysIter::new(Box::new(
-            self.active_data_guard
-                .keys()
-                .chain(self.older_data_guard.keys()),
-        ))
+        StoreKeysIter::new(self.keydir_guard.iter())
     }
 }
 
+/// Yields each live key, skipping deleted and expired ones.
 pub struct StoreKeysIter<'a> {
-    iter: Box<Iterator<Item = &'a Key> + 'a>,
-    seen: HashSet<Key>,
+    iter: Box<dyn Iterator<Item = (&'a [u8], Position)> + 'a>,
+    now: u64,
 }
 
 impl<'a> StoreKeysIter<'a> {
-    fn new(iter: Box<Iterator<Item = &'a Key> + 'a>) -> StoreKeysIter<'a> {
+    fn new(iter: Box<dyn Iterator<Item = (&'a [u8], Position)> + 'a>) -> StoreKeysIter<'a> {
         StoreKeysIter {
             iter,
-            seen: HashSet::new(),
+            now: current_timestamp(),
         }
     }
 }
 
 impl<'a> Iterator for StoreKeysIter<'a> {
-    type Item = &'a Key;
+    type Item = &'a [u8];
 
     fn next(&mut self) -> Option<<Self as Iterator>::Item> {
         loop {
-            let v = self.iter.next();
-            let i = v?;
-            if self.seen.insert(i.clone()) {
-                return v;
-            } else {
-                continue;
+            let (key, pos) = self.iter.next()?;
+            if pos.is_live(self.now) {
+                return Some(key);
             }
         }
     }
@@ -3,16 +3,16 @@
 
 #[macro_use]
 extern crate derive_builder;
+#[macro_use]
 extern crate failure;
 extern crate itertools;
 #[macro_use]
 extern crate log;
 extern crate integer_encoding;
 extern crate io_at;
+extern crate libc;
 extern crate log4rs;
-extern crate regex;
-#[macro_use]
-extern crate lazy_static;
+extern crate memmap;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
@@ -20,16 +20,37 @@ extern crate serde_yaml;
 extern crate test;
 extern crate twox_hash;
 
+mod batch;
+mod compact_keydir;
 mod core;
+mod error;
 mod hint;
+mod keydir;
 mod keys_iterator;
+mod lock;
+mod manifest;
+mod merge;
+mod range_iterator;
+mod rate_limit;
 mod segment;
+mod snapshot;
+mod stats;
 mod store;
+mod transaction;
 
+pub use batch::WriteBatch;
 pub use core::Bitcask;
-pub use core::{Config, ConfigBuilder};
+pub use core::{AutoMerge, Config, ConfigBuilder, MergeLimits, Result, SyncPolicy};
+pub use error::Error;
 
+pub use keydir::{KeyDir, KeyDirFactory, KeyDirKind, KeyDirStats, OrderedEntries};
 pub use keys_iterator::StoreKeys;
+pub use merge::MergeHandle;
+pub use range_iterator::{StoreRange, StoreRangeIter};
+pub use segment::{RecordKind, ValueRef};
+pub use stats::SegmentStats;
+pub use store::Position;
+pub use transaction::Transaction;
 
 use std::sync::{Once, ONCE_INIT};
 
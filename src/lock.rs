@@ -0,0 +1,45 @@
+use core::Result;
+use error::Error;
+use libc;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+
+const LOCK_FILE: &str = "LOCK";
+
+/// Exclusive advisory lock on a store directory, held by the one writer
+/// that has the store open and released when dropped.
+pub struct DirLock {
+    file: File,
+}
+
+impl DirLock {
+    /// Fails with `Error::Locked` if another process, or another open store
+    /// in this process, holds the lock.
+    pub fn acquire(path: &PathBuf) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path.join(LOCK_FILE))?;
+        let ret = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+        if ret != 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::WouldBlock {
+                return Err(Error::Locked(path.clone()));
+            }
+            return Err(e.into());
+        }
+        debug!(target: "bitcask::lock", "locked {:?}", path);
+        Ok(DirLock { file })
+    }
+}
+
+impl Drop for DirLock {
+    fn drop(&mut self) {
+        unsafe {
+            libc::flock(self.file.as_raw_fd(), libc::LOCK_UN);
+        }
+    }
+}
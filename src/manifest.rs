@@ -0,0 +1,178 @@
+use core::Result;
+use error::invalid_data;
+use hint::Hint;
+use segment::Segment;
+use serde_yaml;
+use std::fs::{read_dir, remove_file, rename, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+const MANIFEST_FILE: &str = "MERGE";
+const MANIFEST_TMP_FILE: &str = "MERGE.tmp";
+
+/// Records how a finished merge replaces its input segments. It is written
+/// atomically before any input is touched, so a merge interrupted while
+/// swapping files is rolled forward on open, and one interrupted before the
+/// manifest exists is rolled back by deleting its outputs.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct MergeManifest {
+    /// Merge outputs and the input file ids they take over.
+    pub renames: Vec<(u64, u64)>,
+    /// Inputs with no output taking over their file id.
+    pub removes: Vec<u64>,
+}
+
+impl MergeManifest {
+    pub fn read(path: &Path) -> Result<Option<Self>> {
+        let file = match File::open(path.join(MANIFEST_FILE)) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        serde_yaml::from_reader(file)
+            .map(Some)
+            .map_err(|e| invalid_data(format!("unreadable merge manifest: {}", e)))
+    }
+
+    /// Whether a finished merge is swapping its outputs in, or was cut short
+    /// doing so.
+    pub fn in_progress(path: &Path) -> bool {
+        path.join(MANIFEST_FILE).exists()
+    }
+
+    pub fn write(&self, path: &PathBuf) -> Result<()> {
+        let tmp_path = path.join(MANIFEST_TMP_FILE);
+        {
+            let mut file = OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(true)
+                .open(&tmp_path)?;
+            let content = serde_yaml::to_string(self)
+                .map_err(|e| invalid_data(format!("serialize merge manifest: {}", e)))?;
+            file.write_all(content.as_bytes())?;
+            file.sync_all()?;
+        }
+        rename(&tmp_path, path.join(MANIFEST_FILE))?;
+        sync_dir(path)
+    }
+
+    /// Swap the merge outputs in. Every step can be repeated, so this also
+    /// finishes a swap cut short by a crash.
+    pub fn apply(&self, path: &PathBuf) -> Result<()> {
+        for &(from, to) in &self.renames {
+            rename_if_exists(Segment::get_path(from, path), Segment::get_path(to, path))?;
+            rename_if_exists(Hint::get_path(from, path), Hint::get_path(to, path))?;
+        }
+        for &file_id in &self.removes {
+            remove_if_exists(Segment::get_path(file_id, path))?;
+            remove_if_exists(Hint::get_path(file_id, path))?;
+        }
+        sync_dir(path)?;
+        remove_file(path.join(MANIFEST_FILE))?;
+        sync_dir(path)
+    }
+}
+
+/// Roll an interrupted merge forward if its manifest was written, and delete
+/// the outputs of one that never got that far.
+pub fn recover_merge(path: &PathBuf, min_merge_file_id: u64) -> Result<()> {
+    if let Some(manifest) = MergeManifest::read(path)? {
+        warn!(target: "bitcask::manifest", "finish interrupted merge: {:?}", manifest);
+        manifest.apply(path)?;
+    }
+    for entry in read_dir(path)? {
+        let file_path = entry?.path();
+        let is_merge_output = file_path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.parse::<u64>().ok())
+            .is_some_and(|file_id| file_id >= min_merge_file_id);
+        if is_merge_output {
+            warn!(target: "bitcask::manifest", "remove output of interrupted merge {:?}", file_path);
+            remove_file(&file_path)?;
+        }
+    }
+    remove_if_exists(path.join(MANIFEST_TMP_FILE))
+}
+
+fn rename_if_exists(from: PathBuf, to: PathBuf) -> Result<()> {
+    match rename(&from, &to) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        ret => Ok(ret?),
+    }
+}
+
+fn remove_if_exists(path: PathBuf) -> Result<()> {
+    match remove_file(&path) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        ret => Ok(ret?),
+    }
+}
+
+/// Make renames and removals in `path` durable.
+fn sync_dir(path: &PathBuf) -> Result<()> {
+    File::open(path)?.sync_all()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use segment::RecordKind;
+    use std::env::temp_dir;
+    use std::fs::remove_dir_all;
+
+    fn write_segment(file_id: u64, path: &PathBuf, value: &[u8]) {
+        let mut segment = Segment::new(file_id, path).unwrap();
+        let mut hint = Hint::new(file_id, path).unwrap();
+        let pos = segment.insert(RecordKind::Put, 1, 0, b"a", value).unwrap();
+        hint.insert(&b"a".to_vec(), pos).unwrap();
+    }
+
+    #[test]
+    fn it_rolls_interrupted_merge_forward() {
+        let path = temp_dir().join("bitcask-merge-forward");
+        write_segment(1, &path, b"old");
+        write_segment(2, &path, b"old");
+        write_segment(100, &path, b"merged");
+        let manifest = MergeManifest {
+            renames: vec![(100, 1)],
+            removes: vec![2],
+        };
+        manifest.write(&path).unwrap();
+        // Crash after the segment was renamed but before its hint was.
+        rename(Segment::get_path(100, &path), Segment::get_path(1, &path)).unwrap();
+
+        recover_merge(&path, 100).unwrap();
+        let segment = Segment::open(1, &path).unwrap();
+        assert_eq!(
+            segment.iter().next().unwrap().unwrap().value,
+            b"merged".to_vec()
+        );
+        assert!(Hint::open(1, &path).is_ok());
+        assert!(!Segment::get_path(2, &path).exists());
+        assert!(!Hint::get_path(100, &path).exists());
+        assert_eq!(MergeManifest::read(&path).unwrap(), None);
+
+        remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn it_rolls_unrecorded_merge_back() {
+        let path = temp_dir().join("bitcask-merge-back");
+        write_segment(1, &path, b"old");
+        write_segment(100, &path, b"merged");
+
+        recover_merge(&path, 100).unwrap();
+        let segment = Segment::open(1, &path).unwrap();
+        assert_eq!(
+            segment.iter().next().unwrap().unwrap().value,
+            b"old".to_vec()
+        );
+        assert!(!Segment::get_path(100, &path).exists());
+        assert!(!Hint::get_path(100, &path).exists());
+
+        remove_dir_all(&path).unwrap();
+    }
+}
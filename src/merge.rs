@@ -0,0 +1,128 @@
+use core::{MergeLimits, Result};
+use std::io;
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread::{self, JoinHandle};
+use store::Store;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum State {
+    Running,
+    Paused,
+    Cancelled,
+    Finished,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+impl Shared {
+    fn set(&self, state: State) {
+        let mut current = self.state.lock().expect("lock merge state");
+        if *current != State::Finished {
+            *current = state;
+            self.changed.notify_all();
+        }
+    }
+
+    /// Block while paused. Returns whether to go on with the next step.
+    fn proceed(&self) -> bool {
+        let mut state = self.state.lock().expect("lock merge state");
+        while *state == State::Paused {
+            state = self.changed.wait(state).expect("wait merge state");
+        }
+        *state == State::Running
+    }
+}
+
+/// Controls an incremental merge started by `Bitcask::merge_incremental`.
+/// Every step commits on its own, so pausing or cancelling takes effect
+/// once the step in progress is done and leaves the store consistent.
+/// Dropping the handle lets the merge run to the end.
+pub struct MergeHandle {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<Result<()>>>,
+}
+
+impl MergeHandle {
+    /// Merge the older segments with file ids in `first..=last` from a
+    /// background thread, a step of at most `limits` at a time.
+    pub(crate) fn spawn(
+        store: &Arc<Store>,
+        first: u64,
+        last: u64,
+        limits: MergeLimits,
+        rate_limit: Option<u64>,
+    ) -> Self {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State::Running),
+            changed: Condvar::new(),
+        });
+        let store = Arc::downgrade(store);
+        let thread = {
+            let shared = shared.clone();
+            thread::spawn(move || {
+                let ret = run(&store, &shared, first, last, &limits, rate_limit);
+                if let Err(ref e) = ret {
+                    error!(target: "bitcask::merge", "incremental merge failed: {}", e);
+                }
+                shared.set(State::Finished);
+                ret
+            })
+        };
+        MergeHandle {
+            shared,
+            thread: Some(thread),
+        }
+    }
+
+    /// Stop after the step in progress until `resume` is called.
+    pub fn pause(&self) {
+        self.shared.set(State::Paused);
+    }
+
+    pub fn resume(&self) {
+        self.shared.set(State::Running);
+    }
+
+    /// Stop after the step in progress. Segments merged so far stay merged.
+    pub fn cancel(&self) {
+        self.shared.set(State::Cancelled);
+    }
+
+    /// Whether the merge has run to the end, been cancelled or failed.
+    pub fn is_finished(&self) -> bool {
+        *self.shared.state.lock().expect("lock merge state") == State::Finished
+    }
+
+    /// Block until the merge is done and return the error it stopped on, if
+    /// any. A paused merge has to be resumed or cancelled first.
+    pub fn wait(mut self) -> Result<()> {
+        match self.thread.take().expect("merge thread").join() {
+            Ok(ret) => ret,
+            Err(_) => Err(io::Error::other("merge thread panicked").into()),
+        }
+    }
+}
+
+fn run(
+    store: &Weak<Store>,
+    shared: &Shared,
+    mut next: u64,
+    last: u64,
+    limits: &MergeLimits,
+    rate_limit: Option<u64>,
+) -> Result<()> {
+    while shared.proceed() {
+        let store = match store.upgrade() {
+            Some(store) => store,
+            None => break,
+        };
+        match store.merge_step(next, last, limits, rate_limit)? {
+            Some(resume_from) => next = resume_from,
+            None => break,
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,72 @@
+use core::{Key, Result, Value};
+use std::slice;
+use store::Store;
+
+/// Live keys within a range together with their values, in byte order.
+/// Created by `Bitcask::range` and `Bitcask::scan_prefix`. The keys are
+/// copied out when it is created and each value is read as it is reached,
+/// so writers are not held up; a key deleted in between is skipped.
+pub struct StoreRange<'a> {
+    pub store: &'a Store,
+    pub keys: Vec<Key>,
+}
+
+impl<'a> StoreRange<'a> {
+    pub fn iter(&'a self) -> StoreRangeIter<'a> {
+        StoreRangeIter {
+            store: self.store,
+            keys: self.keys.iter(),
+        }
+    }
+}
+
+impl<'a> IntoIterator for &'a StoreRange<'a> {
+    type Item = Result<(&'a [u8], Value)>;
+    type IntoIter = StoreRangeIter<'a>;
+
+    fn into_iter(self) -> <Self as IntoIterator>::IntoIter {
+        self.iter()
+    }
+}
+
+/// Reads the current value of each key in the range, skipping keys that
+/// are deleted or expired.
+pub struct StoreRangeIter<'a> {
+    store: &'a Store,
+    keys: slice::Iter<'a, Key>,
+}
+
+impl<'a> StoreRangeIter<'a> {
+    /// The value of `key`, or `None` if it is deleted or expired.
+    fn read(&self, key: &'a [u8]) -> Option<Result<(&'a [u8], Value)>> {
+        match self.store.get(key) {
+            Ok(Some(value)) => Some(Ok((key, value))),
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+impl<'a> Iterator for StoreRangeIter<'a> {
+    type Item = Result<(&'a [u8], Value)>;
+
+    fn next(&mut self) -> Option<<Self as Iterator>::Item> {
+        loop {
+            let key = self.keys.next()?;
+            if let Some(ret) = self.read(key) {
+                return Some(ret);
+            }
+        }
+    }
+}
+
+impl<'a> DoubleEndedIterator for StoreRangeIter<'a> {
+    fn next_back(&mut self) -> Option<<Self as Iterator>::Item> {
+        loop {
+            let key = self.keys.next_back()?;
+            if let Some(ret) = self.read(key) {
+                return Some(ret);
+            }
+        }
+    }
+}
@@ -0,0 +1,56 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Token bucket pacing merge I/O to a number of bytes per second. The
+/// bucket holds at most one second worth of tokens, so a merge that went
+/// idle cannot burst past the rate for long.
+pub struct RateLimiter {
+    /// `None` leaves I/O unthrottled.
+    bytes_per_sec: Option<u64>,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_sec: Option<u64>) -> Self {
+        RateLimiter {
+            bytes_per_sec,
+            tokens: bytes_per_sec.unwrap_or(0) as f64,
+            refilled_at: Instant::now(),
+        }
+    }
+
+    /// Take `bytes` tokens, sleeping until the bucket has refilled enough to
+    /// cover them.
+    pub fn acquire(&mut self, bytes: u64) {
+        let rate = match self.bytes_per_sec {
+            Some(rate) => rate as f64,
+            None => return,
+        };
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled_at);
+        let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+        self.refilled_at = now;
+        self.tokens = (self.tokens + elapsed * rate).min(rate) - bytes as f64;
+        if self.tokens < 0.0 {
+            thread::sleep(Duration::from_nanos((-self.tokens / rate * 1e9) as u64));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_paces_to_rate() {
+        let mut limiter = RateLimiter::new(Some(10_000));
+        let start = Instant::now();
+        // The first second worth is already in the bucket.
+        for _ in 0..20 {
+            limiter.acquire(1_000);
+        }
+        assert!(start.elapsed() >= Duration::from_millis(900));
+        assert!(start.elapsed() < Duration::from_secs(3));
+    }
+}
@@ -1,10 +1,18 @@
 use core::{Key, Result, Value};
+use error::{invalid_data, Error};
 use integer_encoding::{VarInt, VarIntReader, VarIntWriter};
 use io_at::Cursor;
+use memmap::{Mmap, MmapOptions};
+use std::fmt;
 use std::fs::{create_dir_all, remove_file, File, OpenOptions};
 use std::hash::Hasher;
-use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::{Deref, Range};
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use store::Position;
 use twox_hash::XxHash;
 
 fn xxhash32(bufs: &[&[u8]]) -> u32 {
@@ -17,79 +25,352 @@ fn xxhash32(bufs: &[&[u8]]) -> u32 {
 
 pub type Offset = u64;
 
-struct SegmentEntry {
-    key: Key,
-    value: Value,
+/// Milliseconds since the unix epoch, as stored in each record.
+pub fn current_timestamp() -> u64 {
+    let elapsed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system time before unix epoch");
+    elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_millis())
 }
 
-impl SegmentEntry {
-    pub fn new_checking_hash(key: Key, value: Value, hash: u32) -> SegmentEntry {
-        let entry = Self::new(key, value);
-        assert_eq!(hash, entry.compute_hash());
-        entry
+const MAGIC: &[u8; 4] = b"BCSK";
+const HEADER_SIZE: u64 = 5;
+/// Segments written before the record header existed carry no magic and
+/// mark deletes with an escaped in-band value.
+const LEGACY_VERSION: u8 = 0;
+/// First version whose records carry a write timestamp.
+const TIMESTAMP_VERSION: u8 = 2;
+/// First version whose records carry an expiry.
+const EXPIRY_VERSION: u8 = 3;
+const VERSION: u8 = 3;
+
+const LEGACY_TOMBSTONE: &[u8] = b"<<>>";
+const LEGACY_ESCAPED_TOMBSTONE: &[u8] = b"<<>><<>>";
+
+/// Set on the kind byte of records written by `Segment::insert_batch`.
+const BATCH_FLAG: u8 = 0x80;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RecordKind {
+    Put,
+    Delete,
+    /// Ends a batch; carries no key or value.
+    Commit,
+}
+
+impl RecordKind {
+    pub fn to_byte(self) -> u8 {
+        match self {
+            RecordKind::Put => 0,
+            RecordKind::Delete => 1,
+            RecordKind::Commit => 2,
+        }
     }
 
-    pub fn new(key: Key, value: Value) -> SegmentEntry {
-        SegmentEntry { key, value }
+    pub fn from_byte(byte: u8) -> Result<RecordKind> {
+        match byte {
+            0 => Ok(RecordKind::Put),
+            1 => Ok(RecordKind::Delete),
+            2 => Ok(RecordKind::Commit),
+            _ => Err(invalid_data(format!("unknown record kind {}", byte))),
+        }
     }
+}
 
-    pub fn compute_size(&self) -> u64 {
-        let key_buf = self.key.as_slice();
-        let key_size_length = (key_buf.len() as u64).required_space();
-        let value_buf = self.value.as_slice();
-        let value_size_length = (value_buf.len() as u64).required_space();
-        let hash_length = self.compute_hash().required_space();
+/// Undo the escaping legacy segments applied to values containing the
+/// tombstone marker.
+fn unescape_legacy_value(value: Value) -> Value {
+    if value.len() < LEGACY_ESCAPED_TOMBSTONE.len() {
+        return value;
+    }
+    let mut unescaped = Vec::with_capacity(value.len());
+    let mut i = 0;
+    while i < value.len() {
+        if value[i..].starts_with(LEGACY_ESCAPED_TOMBSTONE) {
+            unescaped.extend_from_slice(LEGACY_TOMBSTONE);
+            i += LEGACY_ESCAPED_TOMBSTONE.len();
+        } else {
+            unescaped.push(value[i]);
+            i += 1;
+        }
+    }
+    unescaped
+}
 
-        (key_size_length as u64
-            + value_size_length as u64
-            + key_buf.len() as u64
-            + value_buf.len() as u64
-            + hash_length as u64)
+struct SegmentEntry<'a> {
+    kind: RecordKind,
+    batched: bool,
+    tstamp: u64,
+    expiry: u64,
+    key: &'a [u8],
+    value: &'a [u8],
+}
+
+impl<'a> SegmentEntry<'a> {
+    fn kind_byte(&self) -> u8 {
+        if self.batched {
+            self.kind.to_byte() | BATCH_FLAG
+        } else {
+            self.kind.to_byte()
+        }
     }
 
-    pub fn compute_hash(&self) -> u32 {
-        xxhash32(&[self.key.as_slice(), self.value.as_slice()])
+    fn compute_hash(&self, version: u8) -> u32 {
+        let mut meta = vec![self.kind_byte()];
+        if version >= TIMESTAMP_VERSION {
+            meta.extend(self.tstamp.encode_var_vec());
+        }
+        if version >= EXPIRY_VERSION {
+            meta.extend(self.expiry.encode_var_vec());
+        }
+        xxhash32(&[&meta, self.key, self.value])
+    }
+
+    /// Bytes between the start of the record and the start of the value.
+    fn value_offset(&self, version: u8) -> u64 {
+        let mut offset = 1
+            + self.key.len().required_space()
+            + self.key.len()
+            + self.value.len().required_space();
+        if version >= TIMESTAMP_VERSION {
+            offset += self.tstamp.required_space();
+        }
+        if version >= EXPIRY_VERSION {
+            offset += self.expiry.required_space();
+        }
+        offset as u64
+    }
+
+    fn compute_size(&self, version: u8) -> u64 {
+        self.value_offset(version)
+            + self.value.len() as u64
+            + self.compute_hash(version).required_space() as u64
     }
 }
 
-fn read_from_cursor(file: &mut BufReader<Cursor<&File>>) -> Result<SegmentEntry> {
+/// Refuse lengths that run past the end of the segment, so a torn length
+/// varint doesn't turn into a huge allocation.
+fn check_len(len: u64, limit: u64) -> Result<()> {
+    if len > limit {
+        return Err(invalid_data(format!(
+            "record length {} exceeds the {} bytes left in segment",
+            len, limit
+        )));
+    }
+    Ok(())
+}
+
+/// Read the record starting at `offset`, of at most `limit` bytes.
+fn read_from_cursor(
+    file: &mut BufReader<Cursor<&File>>,
+    offset: Offset,
+    limit: u64,
+    version: u8,
+) -> Result<Entry> {
+    if version == LEGACY_VERSION {
+        return read_legacy_from_cursor(file, offset, limit);
+    }
+
+    let mut kind_buf = [0; 1];
+    file.read_exact(&mut kind_buf)?;
+    let kind = RecordKind::from_byte(kind_buf[0] & !BATCH_FLAG)?;
+    let batched = kind_buf[0] & BATCH_FLAG != 0;
+    debug!(target: "bitcask::segment", "get kind {:?}, batched {}", kind, batched);
+    let tstamp = if version >= TIMESTAMP_VERSION {
+        file.read_varint::<u64>()?
+    } else {
+        0
+    };
+    debug!(target: "bitcask::segment", "get tstamp {}", tstamp);
+    let expiry = if version >= EXPIRY_VERSION {
+        file.read_varint::<u64>()?
+    } else {
+        0
+    };
+    debug!(target: "bitcask::segment", "get expiry {}", expiry);
     let key_size = file.read_varint::<u64>()?;
     debug!(target: "bitcask::segment", "get key size {}", key_size);
+    check_len(key_size, limit)?;
     let mut key_buf = vec![0; key_size as usize];
     file.read_exact(&mut key_buf)?;
     debug!(target: "bitcask::segment", "get key buf {:?}", key_buf);
     let value_size = file.read_varint::<u64>()?;
     debug!(target: "bitcask::segment", "get value size {}", value_size);
+    check_len(value_size, limit)?;
     let mut value_buf = vec![0; value_size as usize];
     file.read_exact(&mut value_buf)?;
     debug!(target: "bitcask::segment", "get value buf {:?}", value_buf);
     let hash = file.read_varint::<u32>()?;
     debug!(target: "bitcask::segment", "get hash {}", hash);
-    Ok(SegmentEntry::new_checking_hash(key_buf, value_buf, hash))
+
+    let (value_pos, size) = {
+        let entry = SegmentEntry {
+            kind,
+            batched,
+            tstamp,
+            expiry,
+            key: &key_buf,
+            value: &value_buf,
+        };
+        if hash != entry.compute_hash(version) {
+            return Err(invalid_data("record checksum mismatch"));
+        }
+        (
+            offset + entry.value_offset(version),
+            entry.compute_size(version),
+        )
+    };
+    Ok(Entry {
+        offset,
+        size,
+        value_pos,
+        tstamp,
+        expiry,
+        kind,
+        batched,
+        key: key_buf,
+        value: value_buf,
+    })
+}
+
+fn read_legacy_from_cursor(
+    file: &mut BufReader<Cursor<&File>>,
+    offset: Offset,
+    limit: u64,
+) -> Result<Entry> {
+    let key_size = file.read_varint::<u64>()?;
+    check_len(key_size, limit)?;
+    let mut key_buf = vec![0; key_size as usize];
+    file.read_exact(&mut key_buf)?;
+    let value_size = file.read_varint::<u64>()?;
+    check_len(value_size, limit)?;
+    let mut value_buf = vec![0; value_size as usize];
+    file.read_exact(&mut value_buf)?;
+    let hash = file.read_varint::<u32>()?;
+    if hash != xxhash32(&[key_buf.as_slice(), value_buf.as_slice()]) {
+        return Err(invalid_data("record checksum mismatch"));
+    }
+    debug!(target: "bitcask::segment", "get legacy record, key buf {:?}", key_buf);
+
+    let value_offset = (key_size.required_space() + value_size.required_space()) as u64 + key_size;
+    let (kind, value) = if value_buf.as_slice() == LEGACY_TOMBSTONE {
+        (RecordKind::Delete, vec![])
+    } else {
+        (RecordKind::Put, unescape_legacy_value(value_buf))
+    };
+    Ok(Entry {
+        offset,
+        size: value_offset + value_size + hash.required_space() as u64,
+        value_pos: offset + value_offset,
+        tstamp: 0,
+        expiry: 0,
+        kind,
+        batched: false,
+        key: key_buf,
+        value,
+    })
 }
 
 fn write_at_cursor(entry: &SegmentEntry, file: &mut BufWriter<Cursor<&File>>) -> Result<u64> {
-    let key_buf = entry.key.as_slice();
+    debug!(target: "bitcask::segment", "insert kind {:?}, batched {}", entry.kind, entry.batched);
+    file.write_all(&[entry.kind_byte()])?;
+    debug!(target: "bitcask::segment", "insert tstamp {}", entry.tstamp);
+    file.write_varint(entry.tstamp)?;
+    debug!(target: "bitcask::segment", "insert expiry {}", entry.expiry);
+    file.write_varint(entry.expiry)?;
+    let key_buf = entry.key;
     debug!(target: "bitcask::segment", "insert key size {}", key_buf.len());
     let _key_size_length = file.write_varint(key_buf.len() as u64)?;
     debug!(target: "bitcask::segment", "insert key buf {:?}", key_buf);
     file.write_all(key_buf)?;
-    let value_buf = entry.value.as_slice();
+    let value_buf = entry.value;
     debug!(target: "bitcask::segment", "insert value size {}", value_buf.len());
     let _value_size_length = file.write_varint(value_buf.len() as u64)?;
     debug!(target: "bitcask::segment", "insert value buf {:?}", value_buf);
     file.write_all(value_buf)?;
-    let hash = entry.compute_hash();
+    let hash = entry.compute_hash(VERSION);
     debug!(target: "bitcask::segment", "insert hash {:?}", hash);
     let _hash_length = file.write_varint(hash)?;
-    Ok(entry.compute_size())
+    file.flush()?;
+    Ok(entry.compute_size(VERSION))
+}
+
+/// A value read from the store. Values of memory-mapped segments are not
+/// copied out; the `ValueRef` keeps the mapping alive instead, even past a
+/// merge removing the segment.
+#[derive(Clone)]
+pub struct ValueRef {
+    buf: ValueBuf,
+}
+
+#[derive(Clone)]
+enum ValueBuf {
+    Owned(Value),
+    Mapped {
+        mmap: Arc<Mmap>,
+        start: usize,
+        end: usize,
+    },
+}
+
+impl ValueRef {
+    /// The value as a `Vec`, copying it only if it is mapped.
+    pub fn into_vec(self) -> Value {
+        match self.buf {
+            ValueBuf::Owned(value) => value,
+            ValueBuf::Mapped { .. } => self.to_vec(),
+        }
+    }
+}
+
+impl Deref for ValueRef {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self.buf {
+            ValueBuf::Owned(ref value) => value,
+            ValueBuf::Mapped {
+                ref mmap,
+                start,
+                end,
+            } => &mmap[start..end],
+        }
+    }
+}
+
+impl AsRef<[u8]> for ValueRef {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl From<Value> for ValueRef {
+    fn from(value: Value) -> Self {
+        ValueRef {
+            buf: ValueBuf::Owned(value),
+        }
+    }
+}
+
+impl fmt::Debug for ValueRef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("ValueRef").field(&&**self).finish()
+    }
 }
 
 pub struct Segment {
     file_path: PathBuf,
     pub file_id: u64,
     file: Option<File>,
+    /// Set by `map` once the segment no longer changes.
+    mmap: Option<Arc<Mmap>>,
     pub size: u64,
+    version: u8,
+    /// Times `sync` has been called since the segment was opened.
+    syncs: AtomicU64,
+    /// Set by `check_reads` when the keydir positions in this segment were
+    /// not read from the segment itself.
+    checked_reads: bool,
 }
 
 impl Segment {
@@ -97,69 +378,280 @@ impl Segment {
         path.join(format!("{}.data", file_id))
     }
 
-    pub fn new(file_id: u64, path: &PathBuf) -> Self {
-        create_dir_all(&path).expect("create dir");
+    pub fn new(file_id: u64, path: &PathBuf) -> Result<Self> {
+        create_dir_all(&path)?;
         let file_path = Self::get_path(file_id, path);
-        let file = OpenOptions::new()
+        let mut file = OpenOptions::new()
             .create(true)
             .write(true)
             .truncate(true)
             .read(true)
-            .open(&file_path)
-            .expect("open segment file");
+            .open(&file_path)?;
+        file.write_all(MAGIC)?;
+        file.write_all(&[VERSION])?;
 
         debug!(target: "bitcask::segment", "new segment file {:?}", &file_path);
-        Segment {
+        Ok(Segment {
             file_id,
             file_path,
             file: Some(file),
-            size: 0,
-        }
+            mmap: None,
+            size: HEADER_SIZE,
+            version: VERSION,
+            syncs: AtomicU64::new(0),
+            checked_reads: false,
+        })
     }
 
-    pub fn open(file_id: u64, path: &PathBuf) -> Self {
+    pub fn open(file_id: u64, path: &PathBuf) -> Result<Self> {
         let file_path = Self::get_path(file_id, path);
-        let mut file = OpenOptions::new()
-            .read(true)
-            .open(&file_path)
-            .expect("open segment file");
+        let mut file = OpenOptions::new().read(true).open(&file_path)?;
 
-        let size = file.seek(SeekFrom::End(0)).expect("find file size");
-        Segment {
+        let size = file.seek(SeekFrom::End(0))?;
+        let mut version = LEGACY_VERSION;
+        if size >= HEADER_SIZE {
+            let mut header = [0; HEADER_SIZE as usize];
+            file.seek(SeekFrom::Start(0))?;
+            file.read_exact(&mut header)?;
+            if &header[..MAGIC.len()] == MAGIC {
+                version = header[MAGIC.len()];
+            }
+        }
+        if version > VERSION {
+            return Err(invalid_data(format!(
+                "unsupported segment version {} in {:?}",
+                version, file_path
+            )));
+        }
+        debug!(target: "bitcask::segment", "open segment file {:?}, version {}", file_path, version);
+        Ok(Segment {
             file_id,
             file_path: file_path.clone(),
             file: Some(file),
+            mmap: None,
             size,
+            version,
+            syncs: AtomicU64::new(0),
+            checked_reads: false,
+        })
+    }
+
+    fn data_offset(&self) -> u64 {
+        if self.version == LEGACY_VERSION {
+            0
+        } else {
+            HEADER_SIZE
         }
     }
 
-    pub fn get(&self, offset: Offset) -> Result<Option<Value>> {
-        let mut file = BufReader::new(Cursor::new(self.file.as_ref().expect("get file"), offset));
-        Ok(Some(read_from_cursor(&mut file)?.value))
+    /// Bytes taken by records, i.e. the file size without the header.
+    pub fn data_size(&self) -> u64 {
+        self.size.saturating_sub(self.data_offset())
     }
 
-    pub fn insert(&mut self, key: Key, value: Value) -> Result<Offset> {
+    /// Decode and checksum the whole record on every read, and fail with
+    /// `Error::Corruption` unless it is the one the position describes. Used
+    /// for segments whose positions came from a hint or snapshot, which may
+    /// not belong to the segment now under that file id.
+    pub fn check_reads(&mut self) {
+        self.checked_reads = true;
+    }
+
+    /// Returns `None` if the record at `position` is a delete or has expired.
+    pub fn get(&self, position: &Position) -> Result<Option<Value>> {
+        Ok(self.get_ref(position)?.map(ValueRef::into_vec))
+    }
+
+    /// Like `get`, but borrows the value from the mapping if the segment is
+    /// mapped.
+    pub fn get_ref(&self, position: &Position) -> Result<Option<ValueRef>> {
+        if !position.is_live(current_timestamp()) {
+            return Ok(None);
+        }
+        if self.version == LEGACY_VERSION || self.checked_reads {
+            // Legacy values are escaped on disk, so the whole record has to be
+            // decoded, and checked reads need its checksum.
+            let mut file = BufReader::new(Cursor::new(
+                self.file.as_ref().expect("get file"),
+                position.offset,
+            ));
+            let entry = read_from_cursor(
+                &mut file,
+                position.offset,
+                self.size.saturating_sub(position.offset),
+                self.version,
+            )
+            .map_err(|e| self.corruption_at(position.offset, e))?;
+            if self.checked_reads && entry.position(position.file_id) != *position {
+                debug!(target: "bitcask::segment", "record at offset {} does not match {:?}", position.offset, position);
+                return Err(Error::Corruption {
+                    file_id: self.file_id,
+                    offset: position.offset,
+                });
+            }
+            return Ok(Some(entry.value.into()));
+        }
+
+        if let Some(ref mmap) = self.mmap {
+            let start = position.value_pos as usize;
+            let end = start + position.value_size as usize;
+            if end <= mmap.len() {
+                return Ok(Some(ValueRef {
+                    buf: ValueBuf::Mapped {
+                        mmap: mmap.clone(),
+                        start,
+                        end,
+                    },
+                }));
+            }
+        }
+        let mut value = vec![0; position.value_size as usize];
+        let mut file = Cursor::new(self.file.as_ref().expect("get file"), position.value_pos);
+        file.read_exact(&mut value)?;
+        Ok(Some(value.into()))
+    }
+
+    /// `expiry` is in milliseconds since the unix epoch, 0 meaning never.
+    pub fn insert(
+        &mut self,
+        kind: RecordKind,
+        tstamp: u64,
+        expiry: u64,
+        key: &[u8],
+        value: &[u8],
+    ) -> Result<Position> {
+        self.append(&SegmentEntry {
+            kind,
+            batched: false,
+            tstamp,
+            expiry,
+            key,
+            value,
+        })
+    }
+
+    /// Append `ops` followed by a commit record. When a segment is replayed,
+    /// batch records without their commit are ignored.
+    pub fn insert_batch(
+        &mut self,
+        tstamp: u64,
+        ops: &[(RecordKind, Key, Value)],
+    ) -> Result<Vec<Position>> {
+        let mut positions = Vec::with_capacity(ops.len());
+        for &(kind, ref key, ref value) in ops {
+            positions.push(self.append(&SegmentEntry {
+                kind,
+                batched: true,
+                tstamp,
+                expiry: 0,
+                key,
+                value,
+            })?);
+        }
+        self.append(&SegmentEntry {
+            kind: RecordKind::Commit,
+            batched: false,
+            tstamp,
+            expiry: 0,
+            key: &[],
+            value: &[],
+        })?;
+        Ok(positions)
+    }
+
+    fn append(&mut self, entry: &SegmentEntry) -> Result<Position> {
         let offset = self.size;
         let mut file = BufWriter::new(Cursor::new(self.file.as_ref().expect("get file"), offset));
-        let entry = SegmentEntry::new(key, value);
-        self.size += write_at_cursor(&entry, &mut file)?;
-        Ok(offset)
+        self.size += write_at_cursor(entry, &mut file)?;
+        Ok(Position {
+            file_id: self.file_id,
+            offset,
+            value_pos: offset + entry.value_offset(VERSION),
+            value_size: entry.value.len() as u64,
+            tstamp: entry.tstamp,
+            expiry: entry.expiry,
+            kind: entry.kind,
+        })
+    }
+
+    pub fn sync(&self) -> Result<()> {
+        self.file.as_ref().expect("get file").sync_data()?;
+        self.syncs.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub fn syncs(&self) -> u64 {
+        self.syncs.load(Ordering::Relaxed)
+    }
+
+    /// Serve reads of the first `size` bytes from a memory mapping instead
+    /// of the file. Only for segments nothing appends to or truncates any
+    /// more, since touching a mapped page past the end of the file faults.
+    /// Legacy segments are left unmapped; their values have to be decoded.
+    pub fn map(&mut self) -> Result<()> {
+        if self.version == LEGACY_VERSION || self.size == 0 {
+            return Ok(());
+        }
+        let file = self.file.as_ref().expect("get file");
+        let mmap = unsafe { MmapOptions::new().len(self.size as usize).map(file)? };
+        self.mmap = Some(Arc::new(mmap));
+        Ok(())
+    }
+
+    /// Cut the segment back to `len` bytes, dropping a torn tail.
+    pub fn truncate(&mut self, len: u64) -> Result<()> {
+        self.mmap = None;
+        let file = OpenOptions::new().write(true).open(&self.file_path)?;
+        file.set_len(len)?;
+        file.sync_all()?;
+        self.size = len;
+        Ok(())
     }
 
     pub fn destroy(&mut self) -> Result<()> {
+        self.mmap = None;
         self.file = None;
         remove_file(&self.file_path)?;
         Ok(())
     }
 
+    /// Report a record that could not be decoded as corrupted, passing other
+    /// errors through.
+    fn corruption_at(&self, offset: u64, e: Error) -> Error {
+        match e {
+            Error::Io(ref io_error)
+                if io_error.kind() == io::ErrorKind::UnexpectedEof
+                    || io_error.kind() == io::ErrorKind::InvalidData =>
+            {
+                debug!(target: "bitcask::segment", "bad record at offset {}: {}", offset, io_error);
+                Error::Corruption {
+                    file_id: self.file_id,
+                    offset,
+                }
+            }
+            e => e,
+        }
+    }
+
     pub fn iter(&self) -> SegmentIterator {
-        SegmentIterator::new(self)
+        SegmentIterator::new(self, self.records())
+    }
+
+    /// The records starting in `range`, which has to begin at a record.
+    pub fn iter_range(&self, range: Range<Offset>) -> SegmentIterator<'_> {
+        SegmentIterator::new(self, range)
+    }
+
+    /// Offsets from the first record to the end of the segment.
+    pub fn records(&self) -> Range<Offset> {
+        self.data_offset()..self.size
     }
 }
 
 pub struct SegmentIterator<'a> {
     segment: &'a Segment,
     offset: u64,
+    end: u64,
 }
 
 impl<'a> IntoIterator for &'a Segment {
@@ -167,27 +659,52 @@ impl<'a> IntoIterator for &'a Segment {
     type IntoIter = SegmentIterator<'a>;
 
     fn into_iter(self) -> <Self as IntoIterator>::IntoIter {
-        SegmentIterator::new(self)
+        self.iter()
     }
 }
 
 impl<'a> SegmentIterator<'a> {
-    fn new(segment: &'a Segment) -> SegmentIterator<'a> {
-        SegmentIterator { segment, offset: 0 }
+    fn new(segment: &'a Segment, range: Range<Offset>) -> SegmentIterator<'a> {
+        SegmentIterator {
+            segment,
+            offset: range.start,
+            end: range.end.min(segment.size),
+        }
     }
 }
 
 pub struct Entry {
     pub offset: u64,
+    pub size: u64,
+    pub value_pos: u64,
+    pub tstamp: u64,
+    pub expiry: u64,
+    pub kind: RecordKind,
+    /// Written as part of a batch, valid only once its commit follows.
+    pub batched: bool,
     pub key: Key,
     pub value: Value,
 }
 
+impl Entry {
+    pub fn position(&self, file_id: u64) -> Position {
+        Position {
+            file_id,
+            offset: self.offset,
+            value_pos: self.value_pos,
+            value_size: self.value.len() as u64,
+            tstamp: self.tstamp,
+            expiry: self.expiry,
+            kind: self.kind,
+        }
+    }
+}
+
 impl<'a> Iterator for SegmentIterator<'a> {
     type Item = Result<Entry>;
 
     fn next(&mut self) -> Option<<Self as Iterator>::Item> {
-        if self.offset >= self.segment.size {
+        if self.offset >= self.end {
             return None;
         }
 
@@ -201,16 +718,135 @@ impl<'a> Iterator for SegmentIterator<'a> {
             self.segment.file.as_ref().expect("get file"),
             self.offset,
         ));
-        let segment_entry = read_from_cursor(&mut file).expect("read from cursor");
-        let size = segment_entry.compute_size();
-        let entry = Entry {
-            key: segment_entry.key,
-            value: segment_entry.value,
-            offset: self.offset,
+        let entry = match read_from_cursor(
+            &mut file,
+            self.offset,
+            self.segment.size - self.offset,
+            self.segment.version,
+        ) {
+            Ok(entry) => entry,
+            Err(e) => {
+                let offset = self.offset;
+                // Nothing past a bad record can be located, so stop here.
+                self.offset = self.end;
+                return Some(Err(self.segment.corruption_at(offset, e)));
+            }
         };
 
-        self.offset += size;
+        self.offset += entry.size;
 
         Some(Ok(entry))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+    use std::fs::remove_dir_all;
+
+    fn write_legacy_record(file: &mut File, key: &[u8], value: &[u8]) {
+        file.write_varint(key.len() as u64).unwrap();
+        file.write_all(key).unwrap();
+        file.write_varint(value.len() as u64).unwrap();
+        file.write_all(value).unwrap();
+        file.write_varint(xxhash32(&[key, value])).unwrap();
+    }
+
+    #[test]
+    fn it_can_read_legacy_segment() {
+        let path = temp_dir().join("bitcask-legacy-segment");
+        create_dir_all(&path).unwrap();
+        {
+            let mut file = File::create(Segment::get_path(1, &path)).unwrap();
+            write_legacy_record(&mut file, b"a", b"x<<>><<>>y");
+            write_legacy_record(&mut file, b"a", b"<<>>");
+        }
+
+        let segment = Segment::open(1, &path).unwrap();
+        let entries: Vec<Entry> = segment.iter().map(|e| e.unwrap()).collect();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].kind, RecordKind::Put);
+        assert_eq!(entries[0].value, b"x<<>>y".to_vec());
+        assert_eq!(entries[1].kind, RecordKind::Delete);
+        assert_eq!(
+            segment.get(&entries[0].position(1)).unwrap(),
+            Some(b"x<<>>y".to_vec())
+        );
+        assert_eq!(segment.get(&entries[1].position(1)).unwrap(), None);
+
+        remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn it_reports_checksum_mismatch_as_corruption() {
+        let path = temp_dir().join("bitcask-corrupted-segment");
+        let second = {
+            let mut segment = Segment::new(1, &path).unwrap();
+            segment.insert(RecordKind::Put, 1, 0, b"a", b"x").unwrap();
+            segment.insert(RecordKind::Put, 1, 0, b"b", b"y").unwrap()
+        };
+        {
+            // Overwrite the value of the second record.
+            let mut file = OpenOptions::new()
+                .write(true)
+                .open(Segment::get_path(1, &path))
+                .unwrap();
+            file.seek(SeekFrom::Start(second.value_pos)).unwrap();
+            file.write_all(b"z").unwrap();
+        }
+
+        let segment = Segment::open(1, &path).unwrap();
+        let mut iter = segment.iter();
+        assert_eq!(iter.next().unwrap().unwrap().key, b"a".to_vec());
+        let err = iter.next().unwrap().err().unwrap();
+        match err {
+            Error::Corruption { file_id, offset } => {
+                assert_eq!(file_id, 1);
+                assert_eq!(offset, second.offset);
+            }
+            e => panic!("unexpected error {}", e),
+        }
+        assert!(iter.next().is_none());
+
+        remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn it_rejects_newer_segment_version() {
+        let path = temp_dir().join("bitcask-newer-segment");
+        create_dir_all(&path).unwrap();
+        {
+            let mut file = File::create(Segment::get_path(1, &path)).unwrap();
+            file.write_all(MAGIC).unwrap();
+            file.write_all(&[VERSION + 1]).unwrap();
+        }
+
+        match Segment::open(1, &path) {
+            Err(Error::Io(ref e)) if e.kind() == io::ErrorKind::InvalidData => {}
+            _ => panic!("expected an unsupported version error"),
+        }
+
+        remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn it_can_unescape_legacy_value() {
+        assert_eq!(
+            unescape_legacy_value(b"<<>><<>>".to_vec()),
+            "<<>>".as_bytes().to_vec()
+        );
+        assert_eq!(
+            unescape_legacy_value(b"aa<<>><<>>hel<<>><<>>sdf".to_vec()),
+            "aa<<>>hel<<>>sdf".as_bytes().to_vec()
+        );
+        assert_eq!(
+            unescape_legacy_value(b"<<>><<>><<>><<>>".to_vec()),
+            "<<>><<>>".as_bytes().to_vec()
+        );
+        assert_eq!(
+            unescape_legacy_value(b"<<>>".to_vec()),
+            "<<>>".as_bytes().to_vec()
+        );
+    }
+}
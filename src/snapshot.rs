@@ -0,0 +1,247 @@
+use core::Result;
+use error::invalid_data;
+use integer_encoding::{VarIntReader, VarIntWriter};
+use keydir::KeyDir;
+use segment::RecordKind;
+use stats::{SegmentStats, Stats};
+use std::fs::{remove_file, rename, File, OpenOptions};
+use std::hash::Hasher;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use store::Position;
+use twox_hash::XxHash;
+
+const SNAPSHOT_FILE: &str = "keydir.snapshot";
+const SNAPSHOT_TMP_FILE: &str = "keydir.snapshot.tmp";
+const MAGIC: &[u8; 4] = b"BCKS";
+const VERSION: u8 = 2;
+
+/// What a snapshot covers: every segment below `file_id`, and the records of
+/// segment `file_id` up to `offset`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Covered {
+    pub file_id: u64,
+    pub offset: u64,
+}
+
+/// A snapshot being written to a temporary file, which `commit` moves into
+/// place once it is complete.
+pub struct SnapshotWriter {
+    writer: Checksummed<BufWriter<File>>,
+    path: PathBuf,
+}
+
+impl SnapshotWriter {
+    pub fn create(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(path.join(SNAPSHOT_TMP_FILE))?;
+        Ok(SnapshotWriter {
+            writer: Checksummed {
+                inner: BufWriter::new(file),
+                hasher: XxHash::with_seed(0),
+            },
+            path: path.to_path_buf(),
+        })
+    }
+
+    /// Write out the keydir entries and segment stats of the segments
+    /// `covered` spans.
+    pub fn encode(&mut self, covered: Covered, keydir: &dyn KeyDir, stats: &Stats) -> Result<()> {
+        let buf = &mut self.writer;
+        buf.write_all(MAGIC)?;
+        buf.write_all(&[VERSION])?;
+        buf.write_varint(covered.file_id)?;
+        buf.write_varint(covered.offset)?;
+
+        let segments: Vec<(u64, &SegmentStats)> = stats
+            .iter()
+            .filter(|&(file_id, _)| file_id <= covered.file_id)
+            .collect();
+        buf.write_varint(segments.len() as u64)?;
+        for (file_id, s) in segments {
+            for &n in &[
+                file_id,
+                s.live_keys,
+                s.dead_keys,
+                s.tombstones,
+                s.live_bytes,
+                s.oldest_tstamp,
+                s.newest_tstamp,
+            ] {
+                buf.write_varint(n)?;
+            }
+        }
+
+        let count = keydir
+            .iter()
+            .filter(|&(_, pos)| pos.file_id <= covered.file_id)
+            .count();
+        buf.write_varint(count as u64)?;
+        for (key, pos) in keydir.iter() {
+            if pos.file_id > covered.file_id {
+                continue;
+            }
+            buf.write_varint(key.len() as u64)?;
+            buf.write_all(key)?;
+            for &n in &[
+                pos.file_id,
+                pos.offset,
+                pos.value_pos,
+                pos.value_size,
+                pos.tstamp,
+                pos.expiry,
+            ] {
+                buf.write_varint(n)?;
+            }
+            buf.write_all(&[pos.kind.to_byte()])?;
+        }
+        Ok(())
+    }
+
+    /// End the snapshot with a checksum of everything before it and replace
+    /// the snapshot in the store directory with it atomically.
+    pub fn commit(self) -> Result<()> {
+        let checksum = self.writer.hasher.finish();
+        let mut inner = self.writer.inner;
+        inner.write_all(&checksum.to_le_bytes())?;
+        let file = inner.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        rename(
+            self.path.join(SNAPSHOT_TMP_FILE),
+            self.path.join(SNAPSHOT_FILE),
+        )?;
+        File::open(&self.path)?.sync_all()?;
+        Ok(())
+    }
+}
+
+/// Delete the snapshot in `path`, if there is one.
+pub fn remove(path: &Path) -> Result<()> {
+    match remove_file(path.join(SNAPSHOT_FILE)) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        ret => Ok(ret?),
+    }
+}
+
+/// Hashes everything read or written through it.
+struct Checksummed<T> {
+    inner: T,
+    hasher: XxHash,
+}
+
+impl<W: Write> Write for Checksummed<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.write(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<R: Read> Read for Checksummed<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.write(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// A snapshot whose header has been read, so what it covers can be checked
+/// against the segments on disk before its entries are loaded.
+pub struct SnapshotReader {
+    reader: Checksummed<BufReader<File>>,
+    /// Upper bound for any length read, against corrupted ones.
+    limit: u64,
+    pub covered: Covered,
+}
+
+impl SnapshotReader {
+    pub fn open(path: &Path) -> Result<Option<Self>> {
+        let file = match File::open(path.join(SNAPSHOT_FILE)) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let limit = file.metadata()?.len();
+        let mut reader = Checksummed {
+            inner: BufReader::new(file),
+            hasher: XxHash::with_seed(0),
+        };
+        let mut header = [0; 5];
+        reader.read_exact(&mut header)?;
+        if &header[..MAGIC.len()] != MAGIC || header[MAGIC.len()] != VERSION {
+            return Err(invalid_data("unsupported keydir snapshot"));
+        }
+        let covered = Covered {
+            file_id: reader.read_varint()?,
+            offset: reader.read_varint()?,
+        };
+        Ok(Some(SnapshotReader {
+            reader,
+            limit,
+            covered,
+        }))
+    }
+
+    fn read_len(&mut self) -> Result<u64> {
+        let len = self.reader.read_varint::<u64>()?;
+        if len > self.limit {
+            return Err(invalid_data(format!(
+                "length {} exceeds keydir snapshot size {}",
+                len, self.limit
+            )));
+        }
+        Ok(len)
+    }
+
+    /// Load the entries into `keydir` and the segment counters into `stats`.
+    /// On error part of them may already be loaded.
+    pub fn load(mut self, keydir: &mut dyn KeyDir, stats: &mut Stats) -> Result<()> {
+        for _ in 0..self.read_len()? {
+            let file_id = self.reader.read_varint()?;
+            let segment = SegmentStats {
+                file_id,
+                live_keys: self.reader.read_varint()?,
+                dead_keys: self.reader.read_varint()?,
+                tombstones: self.reader.read_varint()?,
+                live_bytes: self.reader.read_varint()?,
+                oldest_tstamp: self.reader.read_varint()?,
+                newest_tstamp: self.reader.read_varint()?,
+                ..Default::default()
+            };
+            stats.restore(segment);
+        }
+
+        for _ in 0..self.read_len()? {
+            let mut key = vec![0; self.read_len()? as usize];
+            self.reader.read_exact(&mut key)?;
+            let mut pos = Position {
+                file_id: self.reader.read_varint()?,
+                offset: self.reader.read_varint()?,
+                value_pos: self.reader.read_varint()?,
+                value_size: self.reader.read_varint()?,
+                tstamp: self.reader.read_varint()?,
+                expiry: self.reader.read_varint()?,
+                kind: RecordKind::Put,
+            };
+            let mut kind = [0; 1];
+            self.reader.read_exact(&mut kind)?;
+            pos.kind = RecordKind::from_byte(kind[0])?;
+            keydir.insert(key, pos);
+        }
+
+        let expected = self.reader.hasher.finish();
+        let mut checksum = [0; 8];
+        self.reader.inner.read_exact(&mut checksum)?;
+        if u64::from_le_bytes(checksum) != expected {
+            return Err(invalid_data("keydir snapshot checksum mismatch"));
+        }
+        Ok(())
+    }
+}
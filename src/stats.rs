@@ -0,0 +1,98 @@
+use segment::{RecordKind, Segment};
+use std::collections::HashMap;
+use store::Position;
+
+/// Counters for one segment, as returned by `Bitcask::segment_stats`.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct SegmentStats {
+    pub file_id: u64,
+    /// Keys whose current value is in this segment.
+    pub live_keys: u64,
+    /// Records superseded by a later write to their key.
+    pub dead_keys: u64,
+    /// Deletes the keydir still points at.
+    pub tombstones: u64,
+    /// Bytes of the records the keydir points at, tombstones included.
+    pub live_bytes: u64,
+    /// Bytes a merge would reclaim.
+    pub dead_bytes: u64,
+    /// Size of the segment file.
+    pub total_bytes: u64,
+    /// Write time of the oldest record, in milliseconds since the unix epoch.
+    pub oldest_tstamp: u64,
+    /// Write time of the newest record, in milliseconds since the unix epoch.
+    pub newest_tstamp: u64,
+    /// Times the segment was fsynced since the store was opened.
+    pub syncs: u64,
+}
+
+/// Per-segment counters kept up to date as the keydir changes, keyed by
+/// file id. Sizes are filled in from the segment itself by `snapshot`.
+#[derive(Default)]
+pub struct Stats {
+    segments: HashMap<u64, SegmentStats>,
+}
+
+impl Stats {
+    /// Account for the keydir pointing at the record at `pos`.
+    pub fn record_live(&mut self, pos: &Position) {
+        let stats = self.segments.entry(pos.file_id).or_default();
+        if pos.kind == RecordKind::Put {
+            stats.live_keys += 1;
+        } else {
+            stats.tombstones += 1;
+        }
+        stats.live_bytes += pos.record_size();
+        if stats.oldest_tstamp == 0 || pos.tstamp < stats.oldest_tstamp {
+            stats.oldest_tstamp = pos.tstamp;
+        }
+        stats.newest_tstamp = stats.newest_tstamp.max(pos.tstamp);
+    }
+
+    /// Account for the record at `pos` being superseded by a newer one.
+    pub fn record_dead(&mut self, pos: &Position) {
+        let stats = self.segments.entry(pos.file_id).or_default();
+        if pos.kind == RecordKind::Put {
+            stats.live_keys = stats.live_keys.saturating_sub(1);
+        } else {
+            stats.tombstones = stats.tombstones.saturating_sub(1);
+        }
+        stats.live_bytes = stats.live_bytes.saturating_sub(pos.record_size());
+        stats.dead_keys += 1;
+    }
+
+    pub fn snapshot(&self, segment: &Segment) -> SegmentStats {
+        let mut stats = self
+            .segments
+            .get(&segment.file_id)
+            .cloned()
+            .unwrap_or_default();
+        stats.file_id = segment.file_id;
+        stats.total_bytes = segment.size;
+        stats.dead_bytes = segment.data_size().saturating_sub(stats.live_bytes);
+        stats.syncs = segment.syncs();
+        stats
+    }
+
+    pub fn remove(&mut self, file_id: u64) {
+        self.segments.remove(&file_id);
+    }
+
+    /// Counters of every segment with any, keyed by file id.
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item = (u64, &'a SegmentStats)> + 'a {
+        self.segments
+            .iter()
+            .map(|(&file_id, stats)| (file_id, stats))
+    }
+
+    /// Take over the counters of `other`, which has none for the segments
+    /// this has counters for.
+    pub fn extend(&mut self, other: Stats) {
+        self.segments.extend(other.segments);
+    }
+
+    /// Put back counters saved from `iter`.
+    pub fn restore(&mut self, stats: SegmentStats) {
+        self.segments.insert(stats.file_id, stats);
+    }
+}
@@ -1,40 +1,42 @@
-use core::{Config, Key, Result, Value};
+use batch::WriteBatch;
+use compact_keydir;
+use core::{AutoMerge, Config, Key, MergeLimits, Result, SyncPolicy, Value};
+use error::Error;
 use hint::Hint;
+use keydir::{KeyDir, KeyDirKind, KeyDirStats};
 use keys_iterator::StoreKeys;
-use regex::bytes::Regex;
-use segment::{Offset, Segment};
+use lock::DirLock;
+use manifest::{recover_merge, MergeManifest};
+use range_iterator::StoreRange;
+use rate_limit::RateLimiter;
+use segment::{current_timestamp, Offset, RecordKind, Segment, ValueRef};
+use snapshot::{self, Covered, SnapshotReader, SnapshotWriter};
+use stats::{SegmentStats, Stats};
 use std::borrow::Borrow;
-use std::collections::HashMap;
-use std::fs::{create_dir_all, read_dir, rename};
-use std::hash::Hash;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::{create_dir_all, metadata, read_dir, File};
+use std::io;
 use std::mem;
+use std::ops::{Bound, Range};
 use std::path::PathBuf;
-use std::sync::{Arc, RwLock};
-
-pub const TOMBSTONE: &str = "<<>>";
-pub const ESCAPED_TOMBSTONE: &str = "<<>><<>>";
-
-lazy_static! {
-    static ref TOMBSTONE_REGEXP: Regex = Regex::new(TOMBSTONE).expect("regexp");
-    static ref ESCAPED_TOMBSTONE_REGEXP: Regex = Regex::new(ESCAPED_TOMBSTONE).expect("regexp");
-}
-
-pub fn escape_tombstone(value: Value) -> Value {
-    TOMBSTONE_REGEXP
-        .replace_all(&value, ESCAPED_TOMBSTONE.as_bytes())
-        .into_owned()
-}
-
-pub fn unescape_tombstone(value: Value) -> Value {
-    ESCAPED_TOMBSTONE_REGEXP
-        .replace_all(&value, TOMBSTONE.as_bytes())
-        .into_owned()
-}
+use std::sync::{mpsc, Arc, Condvar, Mutex, PoisonError, RwLock};
+use std::thread;
+use std::time::Duration;
 
+/// Keydir entry: where the newest record for a key lives and what it holds.
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub struct Position {
     pub file_id: u64,
+    /// Start of the record.
     pub offset: Offset,
+    /// Start of the value bytes inside the record.
+    pub value_pos: Offset,
+    pub value_size: u64,
+    /// Write time in milliseconds since the unix epoch.
+    pub tstamp: u64,
+    /// Expiry in milliseconds since the unix epoch, 0 meaning never.
+    pub expiry: u64,
+    pub kind: RecordKind,
 }
 
 impl Position {
@@ -42,137 +44,727 @@ impl Position {
         Position {
             file_id: 0,
             offset: 0,
+            value_pos: 0,
+            value_size: 0,
+            tstamp: 0,
+            expiry: 0,
+            kind: RecordKind::Delete,
         }
     }
+
+    /// Whether the record still holds a value at `now`, i.e. it is neither a
+    /// delete nor expired.
+    pub fn is_live(&self, now: u64) -> bool {
+        self.kind == RecordKind::Put && (self.expiry == 0 || self.expiry > now)
+    }
+
+    /// Whether both point at the same write of a key, wherever merges have
+    /// moved its record since. Writes of a key never share a timestamp.
+    pub fn same_write(&self, other: &Position) -> bool {
+        self.tstamp == other.tstamp && self.kind == other.kind
+    }
+
+    /// Bytes taken by the record. The trailing checksum is counted at the
+    /// five bytes nearly all of them take.
+    pub fn record_size(&self) -> u64 {
+        self.value_pos - self.offset + self.value_size + 5
+    }
+}
+
+/// Expiry of a value written now to live for `ttl`. TTLs too long to count
+/// in milliseconds end at the largest timestamp instead of wrapping around.
+fn expiry_after(ttl: Duration) -> u64 {
+    ttl.as_secs()
+        .saturating_mul(1000)
+        .saturating_add(u64::from(ttl.subsec_millis()))
+        .saturating_add(current_timestamp())
 }
 
 #[derive(Default)]
 pub struct MergeResult {
-    merged_hashmap: HashMap<Key, Position>,
+    /// Where each copied key was read from, and where it was written to.
+    merged_hashmap: HashMap<Key, (Position, Position)>,
+    /// Tombstones left out of the output, and the keys they deleted with them.
+    dropped: HashMap<Key, Position>,
     new_file_ids: Vec<u64>,
     to_remove_file_ids: Vec<u64>,
 }
 
+/// The segments a merge copies records into, starting a new one whenever
+/// the current one reaches `max_size_per_segment`.
+struct MergeOutput<'a> {
+    store: &'a Store,
+    segment: Segment,
+    hint: Hint,
+    file_ids: Vec<u64>,
+}
+
+impl<'a> MergeOutput<'a> {
+    fn new(store: &'a Store) -> Result<Self> {
+        let file_id = store.config.min_merge_file_id;
+        Ok(MergeOutput {
+            store,
+            segment: Segment::new(file_id, &store.path)?,
+            hint: Hint::new(file_id, &store.path)?,
+            file_ids: vec![file_id],
+        })
+    }
+
+    fn insert(
+        &mut self,
+        kind: RecordKind,
+        tstamp: u64,
+        expiry: u64,
+        key: &Key,
+        value: &[u8],
+    ) -> Result<Position> {
+        if self.segment.size >= self.store.config.max_size_per_segment {
+            self.store.sync_merged(&self.segment, &self.hint)?;
+            let file_id = self.segment.file_id + 1;
+            self.segment = Segment::new(file_id, &self.store.path)?;
+            self.hint = Hint::new(file_id, &self.store.path)?;
+            self.file_ids.push(file_id);
+        }
+        let pos = self.segment.insert(kind, tstamp, expiry, key, value)?;
+        self.hint.insert(key, pos)?;
+        Ok(pos)
+    }
+
+    /// Sync the last segment and return the file ids written.
+    fn finish(self) -> Result<Vec<u64>> {
+        self.store.sync_merged(&self.segment, &self.hint)?;
+        Ok(self.file_ids)
+    }
+}
+
 pub struct ActiveData {
-    active_segment: Segment,
-    active_hint: Hint,
-    active_hashmap: HashMap<Key, Position>,
+    /// `None` when the store is opened read-only.
+    active_segment: Option<Segment>,
+    active_hint: Option<Hint>,
     pending_segments: HashMap<u64, Segment>,
     pending_hints: HashMap<u64, Hint>,
-    pending_hashmap: HashMap<Key, Position>,
+    /// Records written since the active segment and hint were last synced.
+    unsynced_writes: u64,
     config: Arc<Config>,
 }
 
 impl ActiveData {
-    pub fn get<Q>(&self, key: &Q) -> Result<Option<Value>>
-    where
-        Key: Borrow<Q>,
-        Q: Hash + Eq + ?Sized,
-    {
-        if let Some(pos) = self.active_hashmap.get(key) {
-            return self.active_segment.get(pos.offset);
+    /// The active segment and hint, or `Error::ReadOnly`.
+    fn writable(&mut self) -> Result<(&mut Segment, &mut Hint)> {
+        match (self.active_segment.as_mut(), self.active_hint.as_mut()) {
+            (Some(segment), Some(hint)) => Ok((segment, hint)),
+            _ => Err(Error::ReadOnly),
         }
+    }
 
-        if let Some(pos) = self.pending_hashmap.get(key) {
-            return self
-                .pending_segments
-                .get(&pos.file_id)
-                .map_or(Ok(None), |s| s.get(pos.offset));
-        }
-        Ok(None)
+    pub fn insert(
+        &mut self,
+        kind: RecordKind,
+        tstamp: u64,
+        key: &Key,
+        value: &Value,
+        expiry: u64,
+    ) -> Result<Position> {
+        let (active_segment, active_hint) = self.writable()?;
+        let position = active_segment.insert(kind, tstamp, expiry, key, value)?;
+        active_hint.insert(key, position)?;
+        self.unsynced_writes += 1;
+
+        Ok(position)
     }
 
-    pub fn insert(&mut self, key: Key, value: Value) -> Result<bool> {
-        let active_segment = &mut self.active_segment;
-        let offset = active_segment.insert(key.clone(), value)?;
-        let file_id = active_segment.file_id;
-        let position = Position { offset, file_id };
-        self.active_hint.insert(&key, position)?;
-        let active_hashmap = &mut self.active_hashmap;
-        active_hashmap.insert(key, position);
+    /// Write `key` again as the record at `pos`, keeping its kind, timestamp
+    /// and expiry.
+    fn copy(&mut self, key: &Key, pos: &Position, value: &[u8]) -> Result<Position> {
+        let (active_segment, active_hint) = self.writable()?;
+        let position = active_segment.insert(pos.kind, pos.tstamp, pos.expiry, key, value)?;
+        active_hint.insert(key, position)?;
+        self.unsynced_writes += 1;
 
-        Ok(active_segment.size >= self.config.max_size_per_segment)
+        Ok(position)
     }
 
-    pub fn rotate(&mut self, mut segment: Segment, mut hint: Hint) {
-        let mut new_active_hashmap = HashMap::with_capacity(100);
-        mem::swap(&mut new_active_hashmap, &mut self.active_hashmap);
-        self.pending_hashmap.extend(new_active_hashmap);
+    /// Hints are written only after the batch commit record, so they never
+    /// point at uncommitted records. Returns the position of every op.
+    pub fn write_batch(&mut self, tstamp: u64, batch: &WriteBatch) -> Result<Vec<Position>> {
+        let (active_segment, active_hint) = self.writable()?;
+        let positions = active_segment.insert_batch(tstamp, &batch.ops)?;
+        for ((_, key, _), position) in batch.ops.iter().zip(&positions) {
+            active_hint.insert(key, *position)?;
+        }
+        self.unsynced_writes += 1;
+
+        Ok(positions)
+    }
 
-        assert_eq!(segment.file_id, hint.file_id);
+    /// Whether the active segment has grown past `max_size_per_segment`.
+    fn is_full(&self) -> bool {
+        self.active_segment
+            .as_ref()
+            .is_some_and(|s| s.size >= self.config.max_size_per_segment)
+    }
 
-        mem::swap(&mut self.active_segment, &mut segment);
-        self.pending_segments.insert(segment.file_id, segment);
+    /// The active segment followed by the pending ones.
+    fn segments<'a>(&'a self) -> impl Iterator<Item = &'a Segment> + 'a {
+        self.active_segment
+            .iter()
+            .chain(self.pending_segments.values())
+    }
 
-        mem::swap(&mut self.active_hint, &mut hint);
-        self.pending_hints.insert(hint.file_id, hint);
+    /// The active or pending segment `file_id`.
+    fn segment(&self, file_id: u64) -> Option<&Segment> {
+        self.active_segment
+            .as_ref()
+            .filter(|s| s.file_id == file_id)
+            .or_else(|| self.pending_segments.get(&file_id))
     }
-    //
-    //    pub fn delete(&mut self, key: Key) -> Result<bool> {
-    //        self.insert_raw(key, TOMBSTONE.as_bytes().to_vec())
-    //    }
 
-    pub fn exists(&self, key: &Key) -> Result<bool> {
-        Ok(match self.active_hashmap.get(key) {
-            Some(v) => *v != Position::not_exist(),
-            None => match self.pending_hashmap.get(key) {
-                None => false,
-                Some(v) => *v != Position::not_exist(),
-            },
-        })
+    /// Sync if the writes made since the last sync call for it.
+    pub fn apply_sync_policy(&mut self) -> Result<()> {
+        match self.config.sync_policy {
+            SyncPolicy::EveryWrite => self.sync(),
+            SyncPolicy::EveryN(n) if self.unsynced_writes >= n => self.sync(),
+            _ => Ok(()),
+        }
     }
 
-    pub fn keys<'a>(&'a self) -> Box<Iterator<Item = &'a Key> + 'a> {
-        Box::new(
-            self.active_hashmap
-                .keys()
-                .chain(self.pending_hashmap.keys()),
-        )
+    pub fn sync(&mut self) -> Result<()> {
+        if self.unsynced_writes == 0 {
+            return Ok(());
+        }
+        let (active_segment, active_hint) = self.writable()?;
+        active_segment.sync()?;
+        active_hint.sync()?;
+        self.unsynced_writes = 0;
+        Ok(())
+    }
+
+    pub fn rotate(&mut self, segment: Segment, hint: Hint) -> Result<()> {
+        // Once rotated the segment is no longer covered by `sync`, so it is
+        // synced now whatever the policy.
+        self.sync()?;
+
+        assert_eq!(segment.file_id, hint.file_id);
+
+        if let Some(mut segment) = self.active_segment.replace(segment) {
+            if self.config.mmap_reads {
+                segment.map()?;
+            }
+            self.pending_segments.insert(segment.file_id, segment);
+        }
+        if let Some(hint) = self.active_hint.replace(hint) {
+            self.pending_hints.insert(hint.file_id, hint);
+        }
+        self.unsynced_writes = 0;
+        Ok(())
     }
 }
 
 pub struct OlderData {
     segments: HashMap<u64, Segment>,
     hints: HashMap<u64, Hint>,
-    hashmap: HashMap<Key, Position>,
     config: Arc<Config>,
 }
 
 impl OlderData {
-    pub fn get<Q>(&self, key: &Q) -> Result<Option<Value>>
-    where
-        Key: Borrow<Q>,
-        Q: Eq + Hash + ?Sized,
-    {
-        if let Some(pos) = self.hashmap.get(key) {
-            return self
-                .segments
-                .get(&pos.file_id)
-                .map_or(Ok(None), |s| s.get(pos.offset));
+    /// Load every segment in the store directory into `keydir`, rebuilding
+    /// their `stats`, and return the highest file id found. Segments covered
+    /// by a keydir snapshot are taken from it instead of being read.
+    fn load(
+        config: &Arc<Config>,
+        read_only: bool,
+        keydir: &mut dyn KeyDir,
+        stats: &mut Stats,
+    ) -> Result<(Self, u64)> {
+        let path = &config.path;
+        let mut file_ids = vec![];
+        for entry in read_dir(path)? {
+            let segment_path = entry?.path();
+            if segment_path.extension().is_none_or(|ext| ext != "data") {
+                continue;
+            }
+            let file_id = match segment_path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse::<u64>().ok())
+            {
+                Some(file_id) => file_id,
+                None => {
+                    warn!(target: "bitcask::store::open", "skip stray file {:?}", segment_path);
+                    continue;
+                }
+            };
+            // Outputs of a merge still in progress, seen by read-only stores.
+            if file_id >= config.min_merge_file_id {
+                continue;
+            }
+            file_ids.push(file_id);
+        }
+        // Later segments override earlier ones in the keydir.
+        file_ids.sort();
+        let max_file_id = file_ids.last().cloned().unwrap_or(0);
+        // Only the segment that was active when the store closed can have a
+        // torn tail.
+        let newest_file_id = file_ids.last().cloned();
+
+        let covered = load_snapshot(path, &file_ids, keydir, stats);
+        let (covered_ids, to_load): (Vec<u64>, Vec<u64>) = file_ids
+            .into_iter()
+            .partition(|&file_id| covered.is_some_and(|covered| file_id < covered.file_id));
+        let to_load = match covered {
+            Some(covered) => to_load
+                .into_iter()
+                .filter(|&file_id| file_id > covered.file_id)
+                .collect(),
+            None => to_load,
+        };
+
+        let mut segments = HashMap::with_capacity(100);
+        let mut hints = HashMap::with_capacity(100);
+        for file_id in covered_ids {
+            let mut seg = Segment::open(file_id, path)?;
+            seg.check_reads();
+            if config.mmap_reads && !(Some(file_id) == newest_file_id && read_only) {
+                seg.map()?;
+            }
+            segments.insert(file_id, seg);
+            if let Ok(hint) = Hint::open(file_id, path) {
+                hints.insert(file_id, hint);
+            }
+        }
+        // Only the records past the snapshot are read from the segment it
+        // ends in.
+        if let Some(covered) = covered {
+            let newest = Some(covered.file_id) == newest_file_id;
+            let mut seg = Segment::open(covered.file_id, path)?;
+            seg.check_reads();
+            let hint = load_segment(
+                &mut seg,
+                path,
+                keydir,
+                stats,
+                newest,
+                read_only,
+                covered.offset,
+            )?;
+            if config.mmap_reads && !(newest && read_only) {
+                seg.map()?;
+            }
+            if let Some(hint) = hint {
+                hints.insert(hint.file_id, hint);
+            }
+            segments.insert(covered.file_id, seg);
         }
-        Ok(None)
+        load_segments(config, to_load, newest_file_id, read_only, |loaded| {
+            debug!(target: "bitcask::store::open", "add segment: {:?}", loaded.segment.file_id);
+            stats.extend(loaded.stats);
+            for (key, pos) in loaded.keydir {
+                if let Some(old) = keydir.insert(key, pos) {
+                    stats.record_dead(&old);
+                }
+            }
+            if let Some(hint) = loaded.hint {
+                hints.insert(hint.file_id, hint);
+            }
+            segments.insert(loaded.segment.file_id, loaded.segment);
+        })?;
+        let older_data = OlderData {
+            segments,
+            hints,
+            config: config.clone(),
+        };
+        Ok((older_data, max_file_id))
     }
 
-    pub fn add_segment(&mut self, segment: Segment, hint: Hint) {
+    fn segment(&self, file_id: u64) -> Option<&Segment> {
+        self.segments.get(&file_id)
+    }
+
+    pub fn add_segment(&mut self, mut segment: Segment, hint: Hint) -> Result<()> {
         assert_eq!(segment.file_id, hint.file_id);
+        if self.config.mmap_reads {
+            segment.map()?;
+        }
         self.segments.insert(segment.file_id, segment);
         self.hints.insert(hint.file_id, hint);
+        Ok(())
+    }
+
+    /// Take over the rotated segments still pending in `active_data`.
+    fn promote(&mut self, active_data: &mut ActiveData) {
+        self.segments
+            .extend(mem::take(&mut active_data.pending_segments));
+        self.hints.extend(mem::take(&mut active_data.pending_hints));
     }
 
-    fn remove_segment(&mut self, file_id: u64) -> Result<()> {
-        let seg = self.segments.remove(&file_id);
-        if let Some(mut seg) = seg {
-            seg.destroy()?;
+    /// Close the segment and hint of `file_id`, leaving their files alone.
+    fn remove_segment(&mut self, file_id: u64) {
+        self.segments.remove(&file_id);
+        self.hints.remove(&file_id);
+    }
+}
+
+/// The value at `pos`, from whichever segment holds it. Callers hold both
+/// locks across the keydir lookup and the read, since a finished merge reuses
+/// the file ids of the segments it replaces.
+pub fn read_value(
+    active_data: &ActiveData,
+    older_data: &OlderData,
+    pos: &Position,
+) -> Result<Option<ValueRef>> {
+    active_data
+        .segment(pos.file_id)
+        .or_else(|| older_data.segment(pos.file_id))
+        .map_or(Ok(None), |s| s.get_ref(pos))
+}
+
+/// A segment read on its own, with the keydir entries and stats of just its
+/// records.
+struct LoadedSegment {
+    segment: Segment,
+    hint: Option<Hint>,
+    keydir: HashMap<Key, Position>,
+    stats: Stats,
+}
+
+fn load_one(config: &Config, file_id: u64, newest: bool, read_only: bool) -> Result<LoadedSegment> {
+    let mut segment = Segment::open(file_id, &config.path)?;
+    let mut keydir = HashMap::new();
+    let mut stats = Stats::default();
+    let from = segment.records().start;
+    let hint = load_segment(
+        &mut segment,
+        &config.path,
+        &mut keydir,
+        &mut stats,
+        newest,
+        read_only,
+        from,
+    )?;
+    // The writer may still be appending to the newest segment.
+    if config.mmap_reads && !(newest && read_only) {
+        segment.map()?;
+    }
+    Ok(LoadedSegment {
+        segment,
+        hint,
+        keydir,
+        stats,
+    })
+}
+
+/// Read `file_ids` on `Config::load_threads` threads and hand each segment
+/// to `apply` in file id order, so newer records override older ones just
+/// as if they had been read one after another. Segments are handed out in
+/// order too, at most two per thread ahead of the one being applied, which
+/// bounds how many loaded segments are held at once.
+fn load_segments<F>(
+    config: &Arc<Config>,
+    file_ids: Vec<u64>,
+    newest_file_id: Option<u64>,
+    read_only: bool,
+    mut apply: F,
+) -> Result<()>
+where
+    F: FnMut(LoadedSegment),
+{
+    let threads = config.load_threads.min(file_ids.len());
+    let (job_sender, job_receiver) = mpsc::channel::<(u64, mpsc::Sender<Result<LoadedSegment>>)>();
+    let job_receiver = Arc::new(Mutex::new(job_receiver));
+    for _ in 0..threads {
+        let config = config.clone();
+        let job_receiver = job_receiver.clone();
+        thread::spawn(move || loop {
+            // Ends once every segment is handed out, or loading has failed.
+            let job = job_receiver.lock().expect("lock jobs").recv();
+            let (file_id, done) = match job {
+                Ok(job) => job,
+                Err(_) => break,
+            };
+            let newest = Some(file_id) == newest_file_id;
+            let _ = done.send(load_one(&config, file_id, newest, read_only));
+        });
+    }
+
+    let mut file_ids = file_ids.into_iter();
+    let mut in_flight = VecDeque::new();
+    loop {
+        while in_flight.len() < threads * 2 {
+            let file_id = match file_ids.next() {
+                Some(file_id) => file_id,
+                None => break,
+            };
+            let (done, loaded) = mpsc::channel();
+            job_sender.send((file_id, done)).expect("send job");
+            in_flight.push_back(loaded);
         }
-        let hint = self.hints.remove(&file_id);
-        if let Some(mut h) = hint {
-            h.destroy()?;
+        match in_flight.pop_front() {
+            Some(loaded) => apply(loaded.recv().expect("segment loader exited early")?),
+            None => return Ok(()),
+        }
+    }
+}
+
+/// Load the keydir snapshot in `path`, provided the segments it covers are
+/// still there, and return what it covers. An unusable snapshot is ignored,
+/// leaving every segment to be loaded.
+fn load_snapshot(
+    path: &PathBuf,
+    file_ids: &[u64],
+    keydir: &mut dyn KeyDir,
+    stats: &mut Stats,
+) -> Option<Covered> {
+    let reader = match SnapshotReader::open(path) {
+        Ok(Some(reader)) => reader,
+        Ok(None) => return None,
+        Err(e) => {
+            warn!(target: "bitcask::store::open", "ignore unreadable keydir snapshot: {}", e);
+            return None;
+        }
+    };
+    let covered = reader.covered;
+    let size = metadata(Segment::get_path(covered.file_id, path)).map(|m| m.len());
+    if !file_ids.contains(&covered.file_id) || size.ok().is_none_or(|size| size < covered.offset) {
+        warn!(target: "bitcask::store::open", "ignore keydir snapshot of a changed segment {}", covered.file_id);
+        return None;
+    }
+    match reader.load(keydir, stats) {
+        Ok(()) => {
+            debug!(target: "bitcask::store::open", "load keydir snapshot up to {:?}", covered);
+            Some(covered)
+        }
+        Err(e) => {
+            warn!(target: "bitcask::store::open", "ignore damaged keydir snapshot: {}", e);
+            keydir.clear();
+            *stats = Stats::default();
+            None
+        }
+    }
+}
+
+fn insert_position(hashmap: &mut dyn KeyDir, stats: &mut Stats, key: Key, pos: Position) {
+    stats.record_live(&pos);
+    if let Some(old) = hashmap.insert(key, pos) {
+        stats.record_dead(&old);
+    }
+}
+
+/// Rebuild the keydir entries of `segment`, and its hint if one is given,
+/// from the records in `range`. Batch records only take effect once their
+/// commit record has been read.
+fn replay_segment(
+    segment: &Segment,
+    range: Range<Offset>,
+    mut hint: Option<&mut Hint>,
+    hashmap: &mut dyn KeyDir,
+    stats: &mut Stats,
+) -> Result<()> {
+    let mut batch = vec![];
+    for entry_result in segment.iter_range(range) {
+        let entry = entry_result?;
+        let pos = entry.position(segment.file_id);
+        if entry.kind == RecordKind::Commit {
+            for (key, pos) in batch.drain(..) {
+                if let Some(ref mut hint) = hint {
+                    hint.insert(&key, pos)?;
+                }
+                insert_position(hashmap, stats, key, pos);
+            }
+            continue;
+        }
+        if entry.batched {
+            batch.push((entry.key, pos));
+            continue;
+        }
+        if !batch.is_empty() {
+            warn!(target: "bitcask::store::open", "discard {} records of an uncommitted batch in segment {}", batch.len(), segment.file_id);
+            batch.clear();
+        }
+        if let Some(ref mut hint) = hint {
+            hint.insert(&entry.key, pos)?;
+        }
+        insert_position(hashmap, stats, entry.key, pos);
+    }
+    if !batch.is_empty() {
+        warn!(target: "bitcask::store::open", "discard {} records of an uncommitted batch in segment {}", batch.len(), segment.file_id);
+    }
+    Ok(())
+}
+
+/// Times a store without the directory lock loads again after running into
+/// a merge swapping its outputs in, waiting `MERGE_RETRY_DELAY` longer each
+/// time.
+const MERGE_RETRIES: u32 = 10;
+const MERGE_RETRY_DELAY: Duration = Duration::from_millis(10);
+
+/// `OlderData::load` for a read-only store. A merge swapping its outputs in
+/// renames each segment before its hint, so a load that overlaps the swap
+/// can pair a merged segment with a stale hint. Such a load is thrown away
+/// and retried, and if every retry runs into a merge the load fails with
+/// `Error::MergeInProgress`.
+fn load_unlocked(config: &Arc<Config>) -> Result<(OlderData, u64, Box<dyn KeyDir>, Stats)> {
+    for attempt in 0..=MERGE_RETRIES {
+        if attempt > 0 {
+            thread::sleep(MERGE_RETRY_DELAY * attempt);
+        }
+        if MergeManifest::in_progress(&config.path) {
+            continue;
+        }
+        let mut keydir = config.build_keydir();
+        let mut stats = Stats::default();
+        let loaded = OlderData::load(config, true, &mut *keydir, &mut stats);
+        if MergeManifest::in_progress(&config.path) {
+            debug!(target: "bitcask::store::open", "merge swapped files during load, retry");
+            continue;
+        }
+        let (older_data, max_file_id) = loaded?;
+        return Ok((older_data, max_file_id, keydir, stats));
+    }
+    Err(Error::MergeInProgress)
+}
+
+/// Load the keydir entries of the records of `segment` from offset `from`
+/// on into `hashmap`, from its hint when that is readable. Records before
+/// `from` are already loaded from a keydir snapshot. The newest segment is
+/// always replayed since a crash can leave its hint behind, and a record
+/// torn by the crash is truncated along with anything after it. Read-only
+/// stores write no hints and truncate nothing; they stop at the torn record,
+/// which a live writer may still be appending.
+fn load_segment(
+    segment: &mut Segment,
+    path: &PathBuf,
+    hashmap: &mut dyn KeyDir,
+    stats: &mut Stats,
+    newest: bool,
+    read_only: bool,
+    from: Offset,
+) -> Result<Option<Hint>> {
+    if !newest {
+        if let Ok(hint) = Hint::open(segment.file_id, path) {
+            match hint.iter().collect::<Result<Vec<_>>>() {
+                Ok(entries) => {
+                    for mut entry in entries {
+                        if entry.position.offset < from {
+                            continue;
+                        }
+                        // Merge outputs keep the file id they were written under.
+                        entry.position.file_id = segment.file_id;
+                        insert_position(hashmap, stats, entry.key, entry.position);
+                    }
+                    segment.check_reads();
+                    return Ok(Some(hint));
+                }
+                Err(e) => {
+                    warn!(target: "bitcask::store::open", "rebuild unreadable hint {}: {}", segment.file_id, e)
+                }
+            }
+        }
+    }
+
+    let mut hint = if read_only {
+        None
+    } else {
+        Some(resume_hint(segment, path, from)?)
+    };
+    let err = match replay_segment(segment, from..segment.size, hint.as_mut(), hashmap, stats) {
+        Ok(()) => return Ok(hint),
+        Err(err) => err,
+    };
+    let offset = match err {
+        Error::Corruption { offset, .. } if newest => offset,
+        err => return Err(err),
+    };
+    if read_only {
+        debug!(target: "bitcask::store::open", "stop reading segment {} at offset {}", segment.file_id, offset);
+        segment.size = offset;
+    } else {
+        warn!(target: "bitcask::store::open", "truncate torn tail of segment {}, dropping {} bytes", segment.file_id, segment.size - offset);
+        segment.truncate(offset)?;
+    }
+    Ok(hint)
+}
+
+/// A new hint for `segment` holding the entries of its records before
+/// `from`. They are taken from its current hint, which `write_snapshot`
+/// synced that far, or else from the records themselves.
+fn resume_hint(segment: &Segment, path: &PathBuf, from: Offset) -> Result<Hint> {
+    let records = segment.records();
+    if from <= records.start {
+        return Hint::new(segment.file_id, path);
+    }
+    let entries = Hint::open(segment.file_id, path).map(|hint| {
+        hint.iter()
+            .take_while(Result::is_ok)
+            .filter_map(Result::ok)
+            .filter(|entry| entry.position.offset < from)
+            .collect::<Vec<_>>()
+    });
+    let mut hint = Hint::new(segment.file_id, path)?;
+    match entries {
+        Ok(entries) => {
+            for entry in entries {
+                hint.insert(&entry.key, entry.position)?;
+            }
+        }
+        Err(e) => {
+            warn!(target: "bitcask::store::open", "rebuild unreadable hint {}: {}", segment.file_id, e);
+            let mut scratch = HashMap::new();
+            replay_segment(
+                segment,
+                records.start..from,
+                Some(&mut hint),
+                &mut scratch,
+                &mut Stats::default(),
+            )?;
         }
-        Ok(())
     }
+    Ok(hint)
+}
+
+struct WriteRequest {
+    kind: RecordKind,
+    key: Key,
+    value: Value,
+    expiry: u64,
+}
+
+/// Single-key writes waiting for group commit, and the results of those
+/// already committed.
+#[derive(Default)]
+struct WriteQueue {
+    pending: Vec<(u64, WriteRequest)>,
+    results: HashMap<u64, Result<()>>,
+    next_ticket: u64,
+    leading: bool,
+}
+
+/// Hands the write queue back once the group commit leader is done, even if
+/// it panics, in which case the writes of its group fail rather than leave
+/// their writers waiting forever.
+struct GroupLeader<'a> {
+    store: &'a Store,
+    /// The other writers of the group.
+    tickets: Vec<u64>,
+    results: Vec<(u64, Result<()>)>,
+}
 
-    pub fn keys<'a>(&'a self) -> Box<Iterator<Item = &'a Key> + 'a> {
-        Box::new(self.hashmap.keys())
+impl<'a> Drop for GroupLeader<'a> {
+    fn drop(&mut self) {
+        let mut queue = self
+            .store
+            .write_queue
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if thread::panicking() {
+            for &ticket in &self.tickets {
+                let e = io::Error::other("group commit leader panicked");
+                queue.results.insert(ticket, Err(e.into()));
+            }
+        } else {
+            queue.results.extend(self.results.drain(..));
+        }
+        queue.leading = false;
+        self.store.write_done.notify_all();
     }
 }
 
@@ -181,170 +773,328 @@ pub struct Store {
     next_file_id: RwLock<u64>,
     older_data: RwLock<OlderData>,
     active_data: RwLock<ActiveData>,
+    /// Newest position of every key, across the active, pending and older
+    /// segments. Locked after `active_data` and `older_data`.
+    keydir: RwLock<Box<dyn KeyDir>>,
+    write_queue: Mutex<WriteQueue>,
+    write_done: Condvar,
+    /// Live and dead records per segment, updated as writes supersede them.
+    stats: Mutex<Stats>,
+    /// Held for the whole of a merge so only one runs at a time.
+    merging: Mutex<()>,
     config: Arc<Config>,
+    read_only: bool,
+    /// Held by writable stores until they are dropped.
+    _lock: Option<DirLock>,
 }
 
 impl Store {
-    pub fn new(config: Arc<Config>) -> Self {
+    pub fn new(config: Arc<Config>) -> Result<Self> {
         let path = &config.path;
-        Store {
+        create_dir_all(path)?;
+        let lock = DirLock::acquire(path)?;
+        Ok(Store {
             path: config.path.clone(),
             next_file_id: RwLock::new(1),
             older_data: RwLock::new(OlderData {
                 segments: HashMap::new(),
                 hints: HashMap::new(),
-                hashmap: HashMap::new(),
                 config: config.clone(),
             }),
             active_data: RwLock::new(ActiveData {
-                active_segment: Segment::new(0, path),
-                active_hint: Hint::new(0, path),
-                active_hashmap: HashMap::with_capacity(100),
+                active_segment: Some(Segment::new(0, path)?),
+                active_hint: Some(Hint::new(0, path)?),
                 pending_segments: HashMap::with_capacity(10),
                 pending_hints: HashMap::with_capacity(100),
-                pending_hashmap: HashMap::with_capacity(100),
+                unsynced_writes: 0,
                 config: config.clone(),
             }),
+            keydir: RwLock::new(config.build_keydir()),
+            write_queue: Mutex::new(WriteQueue::default()),
+            write_done: Condvar::new(),
+            stats: Mutex::new(Stats::default()),
+            merging: Mutex::new(()),
             config: config.clone(),
-        }
+            read_only: false,
+            _lock: Some(lock),
+        })
     }
 
-    pub fn open(config: Arc<Config>) -> Self {
+    pub fn open(config: Arc<Config>) -> Result<Self> {
         let path = &config.path;
-
         if !path.exists() {
-            create_dir_all(path).expect("create dir");
+            create_dir_all(path)?;
         }
+        let lock = DirLock::acquire(path)?;
+        recover_merge(path, config.min_merge_file_id)?;
+        Self::load(config, Some(lock))
+    }
 
-        let mut hashmap = HashMap::with_capacity(100);
-        let mut segments = HashMap::with_capacity(100);
-        let mut hints = HashMap::with_capacity(100);
-        let mut max_file_id = 0;
-        for entry in read_dir(path).expect("read segments dir") {
-            let entry = entry.expect("read path entry");
-            let segment_path = entry.path();
-            if segment_path
-                .extension()
-                .expect("get extension")
-                .to_string_lossy()
-                != "data"
-            {
-                continue;
-            }
-            let file_id = segment_path
-                .file_stem()
-                .expect("get file id")
-                .to_str()
-                .expect("to string")
-                .parse::<u64>()
-                .expect("parse int");
-            max_file_id = max_file_id.max(file_id);
-            let seg = Segment::open(file_id, path);
-            let mut hint = Hint::open(file_id, path);
-            match hint {
-                Ok(ref hint) => {
-                    for entry_result in hint {
-                        let entry = entry_result.expect("get hint entry");
-                        hashmap.insert(entry.key.clone(), entry.position);
-                    }
-                }
-                Err(_) => {
-                    let mut h = Hint::new(file_id, path);
-                    for entry_result in &seg {
-                        let entry = entry_result.expect("get entry");
-                        let pos = Position {
-                            file_id,
-                            offset: entry.offset,
-                        };
-                        h.insert(&entry.key, pos).expect("insert");
-                        hashmap.insert(entry.key, pos);
-                    }
-                    hint = Ok(h);
-                }
-            }
+    /// Open an existing store without taking the directory lock or creating
+    /// any file. Writes and merges fail with `Error::ReadOnly`.
+    pub fn open_read_only(config: Arc<Config>) -> Result<Self> {
+        Self::load(config, None)
+    }
 
-            debug!(target: "bitcask::store::open", "add segment: {:?}", file_id);
-            segments.insert(file_id, seg);
-            hints.insert(file_id, hint.unwrap());
-        }
-        Store {
+    /// Build the keydir from the segments in the store directory. Without a
+    /// lock the store is read-only and gets no active segment.
+    fn load(config: Arc<Config>, lock: Option<DirLock>) -> Result<Self> {
+        let path = &config.path;
+        let read_only = lock.is_none();
+        let (older_data, max_file_id, keydir, stats) = if read_only {
+            load_unlocked(&config)?
+        } else {
+            let mut keydir = config.build_keydir();
+            let mut stats = Stats::default();
+            let (older_data, max_file_id) =
+                OlderData::load(&config, false, &mut *keydir, &mut stats)?;
+            (older_data, max_file_id, keydir, stats)
+        };
+        let (active_segment, active_hint) = if read_only {
+            (None, None)
+        } else {
+            (
+                Some(Segment::new(max_file_id + 1, path)?),
+                Some(Hint::new(max_file_id + 1, path)?),
+            )
+        };
+        Ok(Store {
             path: path.clone(),
             next_file_id: RwLock::new(max_file_id + 2),
-            older_data: RwLock::new(OlderData {
-                segments,
-                hints,
-                hashmap,
-                config: config.clone(),
-            }),
+            older_data: RwLock::new(older_data),
             active_data: RwLock::new(ActiveData {
-                active_segment: Segment::new(max_file_id + 1, path),
-                active_hint: Hint::new(max_file_id + 1, path),
-                active_hashmap: HashMap::with_capacity(100),
+                active_segment,
+                active_hint,
                 pending_segments: HashMap::with_capacity(10),
                 pending_hints: HashMap::with_capacity(10),
-                pending_hashmap: HashMap::with_capacity(100),
+                unsynced_writes: 0,
                 config: config.clone(),
             }),
+            keydir: RwLock::new(keydir),
+            write_queue: Mutex::new(WriteQueue::default()),
+            write_done: Condvar::new(),
+            stats: Mutex::new(stats),
+            merging: Mutex::new(()),
             config: config.clone(),
+            read_only,
+            _lock: lock,
+        })
+    }
+
+    /// Reload the keydir of a read-only store to pick up segments written,
+    /// and merges finished, since it was opened. Writable stores already see
+    /// every write, so this does nothing for them.
+    pub fn refresh(&self) -> Result<()> {
+        if !self.read_only {
+            return Ok(());
         }
+        let (older_data, _, keydir, stats) = load_unlocked(&self.config)?;
+        let mut older_data_guard = self.older_data.write().expect("lock write");
+        *older_data_guard = older_data;
+        *self.keydir.write().expect("lock write") = keydir;
+        *self.stats.lock().expect("lock stats") = stats;
+        Ok(())
     }
 
     pub fn get<Q>(&self, key: &Q) -> Result<Option<Value>>
     where
         Key: Borrow<Q>,
-        Q: Eq + Hash + ?Sized,
+        Q: AsRef<[u8]> + ?Sized,
+    {
+        Ok(self.get_bytes(key)?.map(ValueRef::into_vec))
+    }
+
+    pub fn get_bytes<Q>(&self, key: &Q) -> Result<Option<ValueRef>>
+    where
+        Key: Borrow<Q>,
+        Q: AsRef<[u8]> + ?Sized,
     {
-        let ret = self.active_data.read().expect("lock read").get(key)?;
-        if let Some(v) = ret {
-            if v.as_slice() == TOMBSTONE.as_bytes() {
-                return Ok(None);
+        let active_data = self.active_data.read().expect("lock read");
+        let older_data = self.older_data.read().expect("lock read");
+        let pos = self.keydir.read().expect("lock read").get(key.as_ref());
+        match pos {
+            Some(pos) => read_value(&active_data, &older_data, &pos),
+            None => Ok(None),
+        }
+    }
+
+    pub fn insert(&self, key: Key, value: Value) -> Result<()> {
+        self.insert_raw(RecordKind::Put, key, value, 0)
+    }
+
+    pub fn insert_with_ttl(&self, key: Key, value: Value, ttl: Duration) -> Result<()> {
+        self.insert_raw(RecordKind::Put, key, value, expiry_after(ttl))
+    }
+
+    /// Queue the write for group commit. The first writer to find no commit in
+    /// progress becomes the leader: it appends everything queued so far under
+    /// one `active_data` lock, syncs once for the whole group and wakes the
+    /// other writers with their results.
+    fn insert_raw(&self, kind: RecordKind, key: Key, value: Value, expiry: u64) -> Result<()> {
+        let mut queue = self.write_queue.lock().expect("lock write queue");
+        let ticket = queue.next_ticket;
+        queue.next_ticket += 1;
+        queue.pending.push((
+            ticket,
+            WriteRequest {
+                kind,
+                key,
+                value,
+                expiry,
+            },
+        ));
+
+        loop {
+            if let Some(result) = queue.results.remove(&ticket) {
+                return result;
+            }
+            if queue.leading {
+                queue = self.write_done.wait(queue).expect("wait write queue");
+                continue;
             }
-            return Ok(Some(unescape_tombstone(v)));
+
+            queue.leading = true;
+            let group = mem::take(&mut queue.pending);
+            drop(queue);
+            let mut leader = GroupLeader {
+                store: self,
+                tickets: group
+                    .iter()
+                    .map(|&(ticket, _)| ticket)
+                    .filter(|&t| t != ticket)
+                    .collect(),
+                results: vec![],
+            };
+            leader.results = self.commit_group(group);
+            drop(leader);
+            queue = self.write_queue.lock().expect("lock write queue");
         }
+    }
 
-        let ret = self.older_data.read().expect("lock read").get(key)?;
-        if let Some(v) = ret {
-            if v.as_slice() == TOMBSTONE.as_bytes() {
-                return Ok(None);
+    fn commit_group(&self, group: Vec<(u64, WriteRequest)>) -> Vec<(u64, Result<()>)> {
+        debug!(target: "bitcask::store::commit_group", "commit {} writes", group.len());
+        let mut active_data = self.active_data.write().expect("lock write");
+        let mut results: Vec<(u64, Result<()>)> = group
+            .into_iter()
+            .map(|(ticket, req)| {
+                let result =
+                    self.append(&mut active_data, req.kind, req.key, req.value, req.expiry);
+                (ticket, result)
+            })
+            .collect();
+
+        if let Err(e) = active_data.apply_sync_policy() {
+            for &mut (_, ref mut result) in &mut results {
+                if result.is_ok() {
+                    *result = Err(e.duplicate());
+                }
             }
-            return Ok(Some(unescape_tombstone(v)));
         }
+        results
+    }
 
-        Ok(None)
+    /// Append a record while the caller holds the `active_data` write lock.
+    /// Syncing is left to the caller.
+    fn append(
+        &self,
+        active_data: &mut ActiveData,
+        kind: RecordKind,
+        key: Key,
+        value: Value,
+        expiry: u64,
+    ) -> Result<()> {
+        self.check_fits(&key, &value)?;
+        let to_rotate = active_data.is_full();
+        self.rotate_if_needed(active_data, to_rotate)?;
+        let tstamp = self.next_tstamp(Some(&key[..]));
+        let written = active_data.insert(kind, tstamp, &key, &value, expiry)?;
+        self.index(vec![(key, written)]);
+        Ok(())
     }
 
-    pub fn insert(&self, key: Key, value: Value) -> Result<()> {
-        self.insert_raw(key, escape_tombstone(value))
+    /// Append `batch` while the caller holds the `active_data` write lock.
+    /// Syncing is left to the caller.
+    fn append_batch(&self, active_data: &mut ActiveData, batch: WriteBatch) -> Result<()> {
+        for (_, key, value) in &batch.ops {
+            self.check_fits(key, value)?;
+        }
+        let to_rotate = active_data.is_full();
+        self.rotate_if_needed(active_data, to_rotate)?;
+        let tstamp = self.next_tstamp(batch.ops.iter().map(|(_, key, _)| &key[..]));
+        let written = active_data.write_batch(tstamp, &batch)?;
+        self.index(
+            batch
+                .ops
+                .into_iter()
+                .zip(written)
+                .map(|((_, key, _), pos)| (key, pos))
+                .collect(),
+        );
+        Ok(())
     }
 
-    fn insert_raw(&self, key: Key, value: Value) -> Result<()> {
-        let mut active_data = self.active_data.write().expect("lock write");
-        let to_rotate = active_data.insert(key, value)?;
+    /// Timestamp for a write of `keys`: now, or just past the last write of
+    /// any of them if that was in the same millisecond, so that every write
+    /// of a key has a timestamp of its own for `commit` to tell it by.
+    fn next_tstamp<'a, I>(&self, keys: I) -> u64
+    where
+        I: IntoIterator<Item = &'a [u8]>,
+    {
+        let keydir = self.keydir.read().expect("lock read");
+        keys.into_iter()
+            .filter_map(|key| keydir.get(key))
+            .map(|pos| pos.tstamp + 1)
+            .fold(current_timestamp(), u64::max)
+    }
+
+    /// Refuse a key or value too long for a compact keydir to point at,
+    /// before any of it is written.
+    fn check_fits(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        if self.config.keydir == KeyDirKind::Compact
+            && key.len().max(value.len()) as u64 > compact_keydir::MAX_LEN
+        {
+            return Err(Error::TooLarge {
+                max_len: compact_keydir::MAX_LEN,
+            });
+        }
+        Ok(())
+    }
+
+    /// Point the keydir at records just written, in write order, counting
+    /// them as live and whatever they replace as dead. A key written twice
+    /// in one batch replaces its own first record.
+    fn index(&self, written: Vec<(Key, Position)>) {
+        let mut keydir = self.keydir.write().expect("lock write");
+        let mut stats = self.stats.lock().expect("lock stats");
+        for (key, pos) in written {
+            insert_position(&mut **keydir, &mut stats, key, pos);
+        }
+    }
+
+    /// Start a new active segment if `to_rotate`, which writers ask for
+    /// before writing into a full one, so a write that finds no file id left
+    /// fails before anything is appended.
+    fn rotate_if_needed(&self, active_data: &mut ActiveData, to_rotate: bool) -> Result<()> {
         if to_rotate {
             let mut next_file_id = self.next_file_id.write().expect("lock write");
             let file_id = *next_file_id;
+            if file_id >= self.config.max_file_id {
+                return Err(Error::OutOfFileIds {
+                    max_file_id: self.config.max_file_id,
+                });
+            }
             *next_file_id += 1;
             active_data.rotate(
-                Segment::new(file_id, &self.path),
-                Hint::new(file_id, &self.path),
-            );
-            assert!(file_id < self.config.max_file_id);
+                Segment::new(file_id, &self.path)?,
+                Hint::new(file_id, &self.path)?,
+            )?;
         }
 
-        if !active_data.pending_hashmap.is_empty() {
+        if !active_data.pending_segments.is_empty() {
             assert!(!active_data.pending_hints.is_empty());
 
             if let Ok(mut older_data) = self.older_data.try_write() {
-                let mut pending_segments = HashMap::new();
-                let mut pending_hints = HashMap::new();
-                let mut pending_hashmap = HashMap::new();
-                mem::swap(&mut active_data.pending_segments, &mut pending_segments);
-                mem::swap(&mut active_data.pending_hints, &mut pending_hints);
-                mem::swap(&mut active_data.pending_hashmap, &mut pending_hashmap);
-
-                older_data.segments.extend(pending_segments);
-                older_data.hints.extend(pending_hints);
-                older_data.hashmap.extend(pending_hashmap);
+                older_data.promote(active_data);
             }
         }
 
@@ -352,40 +1102,346 @@ impl Store {
     }
 
     pub fn delete(&self, key: Key) -> Result<()> {
-        self.insert_raw(key, TOMBSTONE.as_bytes().to_vec())
+        self.insert_raw(RecordKind::Delete, key, vec![], 0)
+    }
+
+    pub fn sync(&self) -> Result<()> {
+        self.active_data.write().expect("lock write").sync()
+    }
+
+    pub fn write(&self, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let mut active_data = self.active_data.write().expect("lock write");
+        self.append_batch(&mut active_data, batch)?;
+        active_data.apply_sync_policy()
+    }
+
+    /// Apply `batch` only if none of the keys in `reads` has been written
+    /// since it was read at the given position. Records moved by a merge, and
+    /// tombstones it dropped, do not count as writes.
+    pub fn commit(&self, reads: &HashMap<Key, Option<Position>>, batch: WriteBatch) -> Result<()> {
+        let mut active_data = self.active_data.write().expect("lock write");
+        {
+            let keydir = self.keydir.read().expect("lock read");
+            for (key, read_pos) in reads {
+                let unchanged = match (*read_pos, keydir.get(key)) {
+                    (Some(read), Some(current)) => read.same_write(&current),
+                    (Some(pos), None) | (None, Some(pos)) => pos.kind == RecordKind::Delete,
+                    (None, None) => true,
+                };
+                if !unchanged {
+                    return Err(Error::Conflict { key: key.clone() });
+                }
+            }
+        }
+        if batch.is_empty() {
+            return Ok(());
+        }
+        self.append_batch(&mut active_data, batch)?;
+        active_data.apply_sync_policy()
+    }
+
+    /// Read the value of `key` together with the position it was read from.
+    pub fn get_with_position(&self, key: &[u8]) -> Result<(Option<Position>, Option<Value>)> {
+        let active_data = self.active_data.read().expect("lock read");
+        let older_data = self.older_data.read().expect("lock read");
+        let pos = self.keydir.read().expect("lock read").get(key);
+        let value = match pos {
+            Some(ref pos) => read_value(&active_data, &older_data, pos)?,
+            None => None,
+        };
+        Ok((pos, value.map(ValueRef::into_vec)))
     }
+
+    /// Current value of `key` while the caller holds the `active_data` lock.
+    fn get_locked(&self, active_data: &ActiveData, key: &[u8]) -> Result<Option<Value>> {
+        let older_data = self.older_data.read().expect("lock read");
+        let pos = self.keydir.read().expect("lock read").get(key);
+        let value = match pos {
+            Some(ref pos) => read_value(active_data, &older_data, pos)?,
+            None => None,
+        };
+        Ok(value.map(ValueRef::into_vec))
+    }
+
+    /// Replace the value of `key` with `new`, or delete it if `new` is `None`,
+    /// provided its current value equals `expected`. Returns whether the swap
+    /// happened.
+    pub fn compare_and_swap(
+        &self,
+        key: Key,
+        expected: Option<&[u8]>,
+        new: Option<Value>,
+    ) -> Result<bool> {
+        let mut active_data = self.active_data.write().expect("lock write");
+        let current = self.get_locked(&active_data, &key)?;
+        if current.as_deref() != expected {
+            return Ok(false);
+        }
+        match new {
+            Some(value) => self.append(&mut active_data, RecordKind::Put, key, value, 0)?,
+            None => self.append(&mut active_data, RecordKind::Delete, key, vec![], 0)?,
+        }
+        active_data.apply_sync_policy()?;
+        Ok(true)
+    }
+
+    /// Rewrite the current value of `key` with a new time to live. Returns
+    /// `false` if the key does not exist.
+    pub fn expire(&self, key: Key, ttl: Duration) -> Result<bool> {
+        let mut active_data = self.active_data.write().expect("lock write");
+        let value = self.get_locked(&active_data, &key)?;
+        match value {
+            Some(value) => {
+                self.append(
+                    &mut active_data,
+                    RecordKind::Put,
+                    key,
+                    value,
+                    expiry_after(ttl),
+                )?;
+                active_data.apply_sync_policy()?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
     pub fn exists<Q>(&self, key: &Q) -> Result<bool>
     where
         Key: Borrow<Q>,
-        Q: Eq + Hash + ?Sized,
+        Q: AsRef<[u8]> + ?Sized,
     {
-        Ok(self.get(key)?.is_some())
+        let now = current_timestamp();
+        Ok(self.position(key).is_some_and(|pos| pos.is_live(now)))
     }
 
-    fn remove_segment(&self, file_id: u64) -> Result<()> {
-        self.older_data
-            .write()
-            .expect("lock write")
-            .remove_segment(file_id)
-    }
-
-    fn rename_segment(&self, from: u64, to: u64) -> Result<()> {
-        rename(
-            Segment::get_path(from, &self.path),
-            Segment::get_path(to, &self.path),
-        )?;
-        rename(
-            Hint::get_path(from, &self.path),
-            Hint::get_path(to, &self.path),
-        )?;
-        Ok(())
+    fn position<Q>(&self, key: &Q) -> Option<Position>
+    where
+        Key: Borrow<Q>,
+        Q: AsRef<[u8]> + ?Sized,
+    {
+        self.keydir.read().expect("lock read").get(key.as_ref())
+    }
+
+    /// Write time of the newest record for `key`, in milliseconds since the
+    /// unix epoch. Deleted and expired keys have no timestamp.
+    pub fn timestamp<Q>(&self, key: &Q) -> Option<u64>
+    where
+        Key: Borrow<Q>,
+        Q: AsRef<[u8]> + ?Sized,
+    {
+        let now = current_timestamp();
+        self.position(key).and_then(|pos| {
+            if pos.is_live(now) {
+                Some(pos.tstamp)
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Remaining time to live of `key`, or `None` if the key does not exist or
+    /// never expires.
+    pub fn ttl<Q>(&self, key: &Q) -> Option<Duration>
+    where
+        Key: Borrow<Q>,
+        Q: AsRef<[u8]> + ?Sized,
+    {
+        let now = current_timestamp();
+        self.position(key).and_then(|pos| {
+            if pos.is_live(now) && pos.expiry != 0 {
+                Some(Duration::from_millis(pos.expiry - now))
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Live keys within `bounds` and their values, in byte order. Needs an
+    /// ordered keydir.
+    pub fn range(&self, bounds: (Bound<Key>, Bound<Key>)) -> Result<StoreRange<'_>> {
+        let keydir = self.keydir.read().expect("lock read");
+        let now = current_timestamp();
+        let keys = match keydir.range(bounds) {
+            Some(entries) => entries
+                .filter(|&(_, pos)| pos.is_live(now))
+                .map(|(key, _)| key.to_vec())
+                .collect(),
+            None => return Err(Error::Unordered),
+        };
+        Ok(StoreRange { store: self, keys })
     }
 
     pub fn keys(&self) -> StoreKeys {
         StoreKeys {
-            active_data_guard: self.active_data.read().expect("lock read"),
-            older_data_guard: self.older_data.read().expect("lock read"),
+            keydir_guard: self.keydir.read().expect("lock read"),
+        }
+    }
+
+    pub fn keydir_stats(&self) -> KeyDirStats {
+        self.keydir.read().expect("lock read").stats()
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Counters for every segment, ordered by file id.
+    pub fn segment_stats(&self) -> Vec<SegmentStats> {
+        let active_data = self.active_data.read().expect("lock read");
+        let older_data = self.older_data.read().expect("lock read");
+        let stats = self.stats.lock().expect("lock stats");
+        let mut ret: Vec<SegmentStats> = active_data
+            .segments()
+            .chain(older_data.segments.values())
+            .map(|segment| stats.snapshot(segment))
+            .collect();
+        ret.sort_by_key(|s| s.file_id);
+        ret
+    }
+
+    /// The oldest segment `policy` finds fragmented enough, provided at
+    /// least `policy.min_files` segments are. Merges start from there and
+    /// take the segments after it in order, within `policy.limits`, which
+    /// keeps the merged file ids contiguous as replay order relies on.
+    pub fn merge_candidate(&self, policy: &AutoMerge) -> Option<u64> {
+        let older_data = self.older_data.read().expect("lock read");
+        let stats = self.stats.lock().expect("lock stats");
+        let candidates: Vec<u64> = older_data
+            .segments
+            .values()
+            .map(|segment| stats.snapshot(segment))
+            .filter(|s| {
+                s.dead_bytes > 0
+                    && (s.dead_bytes >= policy.dead_bytes
+                        || s.dead_bytes as f64 >= policy.fragmentation * s.total_bytes as f64)
+            })
+            .map(|s| s.file_id)
+            .collect();
+        if candidates.len() < policy.min_files {
+            return None;
+        }
+        candidates.into_iter().min()
+    }
+
+    /// Write a keydir snapshot covering every record written so far, so the
+    /// next open only reads the records written after it. Writers wait while
+    /// the keys are copied out, but not for the syncs.
+    pub fn write_snapshot(&self) -> Result<()> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
         }
+        // A merge finishing in between would leave the snapshot pointing at
+        // the segments it replaced.
+        let _merging = self.merging.lock().expect("lock merging");
+        let mut writer = SnapshotWriter::create(&self.path)?;
+        let covered = {
+            let active_data = self.active_data.read().expect("lock read");
+            let covered = match active_data.active_segment {
+                Some(ref segment) => Covered {
+                    file_id: segment.file_id,
+                    offset: segment.size,
+                },
+                None => return Ok(()),
+            };
+            let keydir = self.keydir.read().expect("lock read");
+            drop(active_data);
+            let stats = self.stats.lock().expect("lock stats");
+            writer.encode(covered, &**keydir, &stats)?;
+            covered
+        };
+        // Nothing the snapshot points at may be lost in a crash. Rotated
+        // segments were synced when rotated, so only the one written to since
+        // is left, along with its hint, which the next open resumes.
+        File::open(Segment::get_path(covered.file_id, &self.path))?.sync_data()?;
+        File::open(Hint::get_path(covered.file_id, &self.path))?.sync_data()?;
+        debug!(target: "bitcask::store::snapshot", "write keydir snapshot up to {:?}", covered);
+        writer.commit()
+    }
+
+    /// Move rotated segments that writers could not hand over to
+    /// `older_data`, because it was locked at the time, so merges see them.
+    fn promote_pending(&self) {
+        let mut active_data = self.active_data.write().expect("lock write");
+        let mut older_data = self.older_data.write().expect("lock write");
+        older_data.promote(&mut active_data);
+    }
+
+    /// Merge every older segment, or those from file id `since` on, holding
+    /// off any other merge until done.
+    pub fn merge_since(&self, since: Option<u64>, rate_limit: Option<u64>) -> Result<()> {
+        let _merging = self.merging.lock().expect("lock merging");
+        self.promote_pending();
+        let file_ids = if let Some(file_id) = since {
+            self.prepare_merging_since(file_id)
+        } else {
+            self.prepare_full_merging()
+        };
+        debug!(target: "bitcask::store::merge", "file_ids: {:?}", file_ids);
+        let ret = self.merge(&file_ids, rate_limit)?;
+        self.finish_merging(ret)
+    }
+
+    /// File ids of the older segments, from the oldest.
+    pub fn older_file_ids(&self) -> Vec<u64> {
+        let mut file_ids: Vec<u64> = self
+            .older_data
+            .read()
+            .expect("lock read")
+            .segments
+            .keys()
+            .cloned()
+            .collect();
+        file_ids.sort();
+        file_ids
+    }
+
+    /// Merge the oldest run of older segments with file ids in `next..=last`
+    /// that fits in `limits`, and return the file id to go on from, or `None`
+    /// if no segment is left in that range. Segments are taken in order, so
+    /// each step merges contiguous file ids.
+    pub fn merge_step(
+        &self,
+        next: u64,
+        last: u64,
+        limits: &MergeLimits,
+        rate_limit: Option<u64>,
+    ) -> Result<Option<u64>> {
+        let _merging = self.merging.lock().expect("lock merging");
+        self.promote_pending();
+        let file_ids = {
+            let older_data = self.older_data.read().expect("lock read");
+            let mut candidates: Vec<&Segment> = older_data
+                .segments
+                .values()
+                .filter(|s| s.file_id >= next && s.file_id <= last)
+                .collect();
+            candidates.sort_by_key(|s| s.file_id);
+            let mut bytes = 0;
+            let mut file_ids = vec![];
+            for segment in candidates {
+                if !file_ids.is_empty()
+                    && (file_ids.len() >= limits.max_segments
+                        || bytes + segment.size > limits.max_bytes)
+                {
+                    break;
+                }
+                bytes += segment.size;
+                file_ids.push(segment.file_id);
+            }
+            file_ids
+        };
+        let resume_from = match file_ids.last() {
+            Some(&file_id) => file_id + 1,
+            None => return Ok(None),
+        };
+        debug!(target: "bitcask::store::merge", "step file_ids: {:?}", file_ids);
+        let ret = self.merge(&file_ids, rate_limit)?;
+        self.finish_merging(ret)?;
+        Ok(Some(resume_from))
     }
 
     pub fn prepare_full_merging(&self) -> Vec<u64> {
@@ -411,83 +1467,260 @@ impl Store {
             .collect()
     }
 
-    pub fn merge(&self, file_ids: &[u64]) -> Result<MergeResult> {
+    /// Copy the live records of `file_ids` into new segments, reading and
+    /// writing at most `rate_limit` bytes per second.
+    pub fn merge(&self, file_ids: &[u64], rate_limit: Option<u64>) -> Result<MergeResult> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
         if file_ids.is_empty() {
             return Ok(MergeResult::default());
         }
         // todo: check file ids are continued
-        let older_data = self.older_data.read().expect("lock read");
-        let hashmap = &older_data.hashmap;
-        let mut new_hashmap: HashMap<Key, Position> = HashMap::with_capacity(hashmap.capacity());
-        let mut next_file_id = self.config.min_merge_file_id;
-
-        let mut new_file_ids = vec![next_file_id];
+        // The inputs are read from their files, which only a merge replaces,
+        // so `older_data` is left unlocked while they are copied.
+        let mut new_hashmap = HashMap::new();
         let mut to_remove_file_ids = vec![];
-        let mut new_segment = Segment::new(next_file_id, &self.path);
-        let mut new_hint = Hint::new(next_file_id, &self.path);
-        next_file_id += 1;
+        let mut output = MergeOutput::new(self)?;
+        let now = current_timestamp();
+        let mut limiter = RateLimiter::new(rate_limit);
+        // Deletes, and values that have expired, are held back until it is
+        // known whether they still hide a version of their key.
+        let mut tombstones = vec![];
 
         for file_id in file_ids {
-            let segment = Segment::open(*file_id, &self.path);
+            let segment = Segment::open(*file_id, &self.path)?;
             for kv_result in segment.iter() {
                 let entry = kv_result?;
-                match hashmap.get(&entry.key) {
-                    None => continue,
-                    Some(&pos) => {
-                        if segment.file_id == pos.file_id && entry.offset == pos.offset {
-                            if new_segment.size >= self.config.max_size_per_segment {
-                                new_file_ids.push(next_file_id);
-                                new_segment = Segment::new(next_file_id, &self.path);
-                                new_hint = Hint::new(next_file_id, &self.path);
-                                next_file_id += 1;
-                            }
-                            let offset = new_segment.insert(entry.key.clone(), entry.value)?;
-                            let pos = Position {
-                                file_id: new_segment.file_id,
-                                offset,
-                            };
-                            new_hint.insert(&entry.key, pos)?;
-                            new_hashmap.insert(entry.key, pos);
+                limiter.acquire(entry.size);
+                let current = self.keydir.read().expect("lock read").get(&entry.key);
+                match current {
+                    Some(pos) if segment.file_id == pos.file_id && entry.offset == pos.offset => {
+                        if !pos.is_live(now) {
+                            tombstones.push((entry.key, pos));
+                            continue;
                         }
+                        let new_pos = output.insert(
+                            entry.kind,
+                            entry.tstamp,
+                            entry.expiry,
+                            &entry.key,
+                            &entry.value,
+                        )?;
+                        limiter.acquire(new_pos.record_size());
+                        new_hashmap.insert(entry.key, (pos, new_pos));
                     }
+                    _ => continue,
                 }
             }
             to_remove_file_ids.push(segment.file_id);
         }
 
+        let oldest_merged = file_ids.iter().cloned().min().unwrap_or(0);
+        let shadowed = {
+            let older_data = self.older_data.read().expect("lock read");
+            self.shadowed_keys(&older_data, oldest_merged, &tombstones)
+        };
+        let mut dropped = HashMap::new();
+        for (key, pos) in tombstones {
+            if !shadowed.contains(&key) {
+                dropped.insert(key, pos);
+                continue;
+            }
+            let new_pos = output.insert(RecordKind::Delete, pos.tstamp, 0, &key, &[])?;
+            limiter.acquire(new_pos.record_size());
+            new_hashmap.insert(key, (pos, new_pos));
+        }
+        debug!(target: "bitcask::store::merge", "drop {} tombstones", dropped.len());
+
         Ok(MergeResult {
             merged_hashmap: new_hashmap,
-            new_file_ids,
+            dropped,
+            new_file_ids: output.finish()?,
             to_remove_file_ids,
         })
     }
 
+    /// The keys of `tombstones` that an older segment below file id `below`
+    /// may still have a record for, going by the segments' hints. A segment
+    /// without a readable hint is taken to have them all.
+    fn shadowed_keys(
+        &self,
+        older_data: &OlderData,
+        below: u64,
+        tombstones: &[(Key, Position)],
+    ) -> HashSet<Key> {
+        let mut shadowed = HashSet::new();
+        if tombstones.is_empty() {
+            return shadowed;
+        }
+        let wanted: HashSet<&Key> = tombstones.iter().map(|(key, _)| key).collect();
+        for (file_id, segment) in &older_data.segments {
+            if *file_id >= below {
+                continue;
+            }
+            let entries = older_data
+                .hints
+                .get(file_id)
+                .map(|hint| hint.iter().collect::<Result<Vec<_>>>());
+            match entries {
+                Some(Ok(entries)) => {
+                    for entry in entries {
+                        if wanted.contains(&entry.key) {
+                            shadowed.insert(entry.key);
+                        }
+                    }
+                }
+                _ => {
+                    warn!(target: "bitcask::store::merge", "keep tombstones, no readable hint for segment {}", segment.file_id);
+                    return wanted.into_iter().cloned().collect();
+                }
+            }
+        }
+        shadowed
+    }
+
+    /// Merge outputs replace the original segments, so they are synced before
+    /// the manifest is written whatever the sync policy, or a crash could
+    /// keep the renames and lose the data.
+    fn sync_merged(&self, segment: &Segment, hint: &Hint) -> Result<()> {
+        segment.sync()?;
+        hint.sync()
+    }
+
+    /// Give the merge outputs that outnumber the inputs fresh file ids,
+    /// appended to `targets`, and start a new active segment above them.
+    /// Records written during the merge can be in segments those ids replay
+    /// after, so the newest record of every key an extra output holds a
+    /// stale copy of is written again into the new active segment.
+    fn place_extra_outputs(
+        &self,
+        merge_result: &MergeResult,
+        targets: &mut Vec<u64>,
+    ) -> Result<()> {
+        let extra_outputs = &merge_result.new_file_ids[targets.len()..];
+        let mut active_data = self.active_data.write().expect("lock write");
+        {
+            let mut next_file_id = self.next_file_id.write().expect("lock write");
+            // One more for the new active segment.
+            if *next_file_id + extra_outputs.len() as u64 >= self.config.max_file_id {
+                return Err(Error::OutOfFileIds {
+                    max_file_id: self.config.max_file_id,
+                });
+            }
+            for _ in extra_outputs {
+                targets.push(*next_file_id);
+                *next_file_id += 1;
+            }
+        }
+        self.rotate_if_needed(&mut active_data, true)?;
+
+        let stale = {
+            let older_data = self.older_data.read().expect("lock read");
+            let keydir = self.keydir.read().expect("lock read");
+            let mut stale = vec![];
+            for (key, &(old_pos, new_pos)) in &merge_result.merged_hashmap {
+                if !extra_outputs.contains(&new_pos.file_id) {
+                    continue;
+                }
+                match keydir.get(key) {
+                    Some(pos) if pos != old_pos => {
+                        let value = read_value(&active_data, &older_data, &pos)?
+                            .map(ValueRef::into_vec)
+                            .unwrap_or_default();
+                        stale.push((key.clone(), pos, value));
+                    }
+                    _ => {}
+                }
+            }
+            stale
+        };
+        debug!(target: "bitcask::store::finish_merging", "rewrite {} keys written during the merge", stale.len());
+        let mut written = Vec::with_capacity(stale.len());
+        for (key, pos, value) in stale {
+            let position = active_data.copy(&key, &pos, &value)?;
+            written.push((key, position));
+        }
+        active_data.sync()?;
+        self.index(written);
+        Ok(())
+    }
+
+    /// Swap the merge outputs in for the merged segments. Outputs take over
+    /// the lowest input file ids so they keep their place in replay order,
+    /// and any left over take fresh ones from `place_extra_outputs`; the swap
+    /// is recorded in a `MergeManifest` first so a crash part way through is
+    /// finished on the next open.
     pub fn finish_merging(&self, mut merge_result: MergeResult) -> Result<()> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
         debug!(target: "bitcask::store::finish_merging", "new_file_ids: {:?}, to_remove_file_ids: {:?}", merge_result.new_file_ids, merge_result.to_remove_file_ids);
-        assert!(merge_result.new_file_ids.len() <= merge_result.to_remove_file_ids.len());
+        if merge_result.to_remove_file_ids.is_empty() {
+            return Ok(());
+        }
+        let mut targets = merge_result.to_remove_file_ids.clone();
+        targets.sort();
+        if merge_result.new_file_ids.len() > targets.len() {
+            if let Err(e) = self.place_extra_outputs(&merge_result, &mut targets) {
+                for &file_id in &merge_result.new_file_ids {
+                    Segment::open(file_id, &self.path)?.destroy()?;
+                    Hint::open(file_id, &self.path)?.destroy()?;
+                }
+                return Err(e);
+            }
+        }
+        let manifest = MergeManifest {
+            renames: merge_result
+                .new_file_ids
+                .iter()
+                .cloned()
+                .zip(targets.iter().cloned())
+                .collect(),
+            removes: targets[merge_result.new_file_ids.len()..].to_vec(),
+        };
 
         let mut older_data = self.older_data.write().expect("lock write");
-        let mut hashmap = HashMap::new();
-        mem::swap(&mut hashmap, &mut merge_result.merged_hashmap);
-
-        for i in &merge_result.to_remove_file_ids {
-            older_data.remove_segment(*i)?;
+        // The snapshot points into the segments about to be replaced.
+        snapshot::remove(&self.path)?;
+        manifest.write(&self.path)?;
+        {
+            let mut stats = self.stats.lock().expect("lock stats");
+            for file_id in &merge_result.to_remove_file_ids {
+                older_data.remove_segment(*file_id);
+                stats.remove(*file_id);
+            }
         }
+        manifest.apply(&self.path)?;
+
         let mut mapping = HashMap::new();
-        for (i, from_file_id) in merge_result.new_file_ids.iter().enumerate() {
-            let to_file_id = merge_result.to_remove_file_ids[i];
-            self.rename_segment(*from_file_id, to_file_id)?;
-            mapping.insert(*from_file_id, to_file_id);
+        for &(from_file_id, to_file_id) in &manifest.renames {
+            mapping.insert(from_file_id, to_file_id);
             older_data.add_segment(
-                Segment::open(to_file_id, &self.path),
+                Segment::open(to_file_id, &self.path)?,
                 Hint::open(to_file_id, &self.path)?,
-            );
+            )?;
+        }
+        let mut hashmap = HashMap::new();
+        mem::swap(&mut hashmap, &mut merge_result.merged_hashmap);
+        let mut keydir = self.keydir.write().expect("lock write");
+        let mut stats = self.stats.lock().expect("lock stats");
+        for (key, (old_pos, mut new_pos)) in hashmap {
+            new_pos.file_id = mapping[&new_pos.file_id];
+            stats.record_live(&new_pos);
+            // Keys written since the merge read them keep their newer
+            // position.
+            if keydir.get(&key) == Some(old_pos) {
+                keydir.insert(key, new_pos);
+            } else {
+                stats.record_dead(&new_pos);
+            }
         }
-        for v in hashmap.values_mut() {
-            v.file_id = mapping[&v.file_id];
+        for (key, pos) in merge_result.dropped {
+            if keydir.get(&key) == Some(pos) {
+                keydir.remove(&key);
+            }
         }
-
-        older_data.hashmap.extend(hashmap);
         Ok(())
     }
 }
@@ -495,48 +1728,136 @@ impl Store {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use test::Bencher;
+    use std::env::temp_dir;
+    use std::fs::{remove_dir_all, OpenOptions};
+
+    #[test]
+    fn it_ignores_uncommitted_batch_on_replay() {
+        let path = temp_dir().join("bitcask-uncommitted-batch");
+        {
+            let mut segment = Segment::new(1, &path).unwrap();
+            segment.insert(RecordKind::Put, 1, 0, b"a", b"1").unwrap();
+            segment
+                .insert_batch(
+                    2,
+                    &[
+                        (RecordKind::Put, b"b".to_vec(), b"2".to_vec()),
+                        (RecordKind::Delete, b"a".to_vec(), vec![]),
+                    ],
+                )
+                .unwrap();
+        }
+
+        let commit_offset = Segment::open(1, &path)
+            .unwrap()
+            .iter()
+            .map(|e| e.unwrap())
+            .find(|e| e.kind == RecordKind::Commit)
+            .unwrap()
+            .offset;
+        let mut hashmap = KeyDirKind::Hashed.build();
+        let segment = Segment::open(1, &path).unwrap();
+        replay_segment(
+            &segment,
+            segment.records(),
+            Some(&mut Hint::new(1, &path).unwrap()),
+            &mut *hashmap,
+            &mut Stats::default(),
+        )
+        .unwrap();
+        assert_eq!(hashmap.len(), 2);
+        assert_eq!(hashmap.get(&b"a"[..]).unwrap().kind, RecordKind::Delete);
 
-    #[bench]
-    fn bench_escape_tombstone(b: &mut Bencher) {
-        let value: Vec<u8> = vec![0; 512];
-        b.iter(|| escape_tombstone(value.clone()));
+        OpenOptions::new()
+            .write(true)
+            .open(Segment::get_path(1, &path))
+            .unwrap()
+            .set_len(commit_offset)
+            .unwrap();
+        let mut hashmap = KeyDirKind::Hashed.build();
+        let segment = Segment::open(1, &path).unwrap();
+        replay_segment(
+            &segment,
+            segment.records(),
+            Some(&mut Hint::new(1, &path).unwrap()),
+            &mut *hashmap,
+            &mut Stats::default(),
+        )
+        .unwrap();
+        assert_eq!(hashmap.len(), 1);
+        assert_eq!(hashmap.get(&b"a"[..]).unwrap().kind, RecordKind::Put);
+
+        remove_dir_all(&path).unwrap();
     }
 
     #[test]
-    fn it_can_escape() {
-        assert_eq!(
-            escape_tombstone(b"<<>>".to_vec()),
-            "<<>><<>>".as_bytes().to_vec()
-        );
-        assert_eq!(
-            escape_tombstone(b"aa<<>>hel<<>>sdf".to_vec()),
-            "aa<<>><<>>hel<<>><<>>sdf".as_bytes().to_vec()
-        );
-        assert_eq!(
-            escape_tombstone(b"<<>><<>>".to_vec()),
-            "<<>><<>><<>><<>>".as_bytes().to_vec()
-        );
+    fn it_keeps_keys_written_during_merge() {
+        let path = temp_dir().join("bitcask-merge-reconcile");
+        let _ = remove_dir_all(&path);
+        {
+            let config = Config {
+                path: path.clone(),
+                max_size_per_segment: 64,
+                ..Default::default()
+            };
+            let store = Store::open(Arc::new(config)).unwrap();
+            for i in 0..10u8 {
+                store.insert(vec![i], vec![0; 16]).unwrap();
+            }
+            store.promote_pending();
+            let merge_result = store.merge(&store.older_file_ids(), None).unwrap();
+
+            // Overwritten after the merge read them, and promoted to older
+            // data before it commits.
+            for i in 0..10u8 {
+                store.insert(vec![i], vec![1; 16]).unwrap();
+            }
+            store.promote_pending();
+            store.finish_merging(merge_result).unwrap();
+            for i in 0..10u8 {
+                assert_eq!(store.get(&vec![i]).unwrap(), Some(vec![1; 16]));
+            }
+        }
+        remove_dir_all(&path).unwrap();
     }
 
     #[test]
-    fn it_can_unescape() {
-        assert_eq!(
-            unescape_tombstone(b"<<>><<>>".to_vec()),
-            "<<>>".as_bytes().to_vec()
-        );
-        assert_eq!(
-            unescape_tombstone(b"aa<<>><<>>hel<<>><<>>sdf".to_vec()),
-            "aa<<>>hel<<>>sdf".as_bytes().to_vec()
-        );
-        assert_eq!(
-            unescape_tombstone(b"<<>><<>><<>><<>>".to_vec()),
-            "<<>><<>>".as_bytes().to_vec()
-        );
-        assert_eq!(
-            unescape_tombstone(b"<<>>".to_vec()),
-            "<<>>".as_bytes().to_vec()
-        );
+    fn it_gives_extra_merge_outputs_fresh_file_ids() {
+        let path = temp_dir().join("bitcask-merge-extra-outputs");
+        let _ = remove_dir_all(&path);
+        let config = |max_size_per_segment| {
+            Arc::new(Config {
+                path: path.clone(),
+                max_size_per_segment,
+                ..Default::default()
+            })
+        };
+        {
+            let store = Store::open(config(1024)).unwrap();
+            for i in 0..10u8 {
+                store.insert(vec![i], vec![0; 16]).unwrap();
+            }
+        }
+        {
+            // The single segment written above merges into several.
+            let store = Store::open(config(64)).unwrap();
+            let merge_result = store.merge(&store.older_file_ids(), None).unwrap();
+            assert!(merge_result.new_file_ids.len() > 1);
+            for i in 0..5u8 {
+                store.insert(vec![i], vec![1; 16]).unwrap();
+            }
+            store.finish_merging(merge_result).unwrap();
+            for i in 0..10u8 {
+                let value = if i < 5 { 1 } else { 0 };
+                assert_eq!(store.get(&vec![i]).unwrap(), Some(vec![value; 16]));
+            }
+        }
+        let store = Store::open(config(64)).unwrap();
+        for i in 0..10u8 {
+            let value = if i < 5 { 1 } else { 0 };
+            assert_eq!(store.get(&vec![i]).unwrap(), Some(vec![value; 16]));
+        }
+        drop(store);
+        remove_dir_all(&path).unwrap();
     }
-
 }
@@ -0,0 +1,55 @@
+use batch::WriteBatch;
+use core::{Key, Result, Value};
+use std::collections::HashMap;
+use std::sync::Arc;
+use store::{Position, Store};
+
+/// An optimistic read-modify-write transaction. Reads are recorded by the
+/// position they were served from and writes are buffered; `commit` applies
+/// the writes atomically only if no read key has changed in the meantime.
+pub struct Transaction {
+    store: Arc<Store>,
+    reads: HashMap<Key, Option<Position>>,
+    writes: HashMap<Key, Option<Value>>,
+}
+
+impl Transaction {
+    pub fn new(store: Arc<Store>) -> Self {
+        Transaction {
+            store,
+            reads: HashMap::new(),
+            writes: HashMap::new(),
+        }
+    }
+
+    /// Reads see the transaction's own buffered writes.
+    pub fn get(&mut self, key: &[u8]) -> Result<Option<Value>> {
+        if let Some(value) = self.writes.get(key) {
+            return Ok(value.clone());
+        }
+        let (pos, value) = self.store.get_with_position(key)?;
+        self.reads.entry(key.to_vec()).or_insert(pos);
+        Ok(value)
+    }
+
+    pub fn set(&mut self, key: Key, value: Value) {
+        self.writes.insert(key, Some(value));
+    }
+
+    pub fn delete(&mut self, key: Key) {
+        self.writes.insert(key, None);
+    }
+
+    /// Fails with `Error::Conflict` if any key read by the transaction has been
+    /// written since; nothing is applied in that case.
+    pub fn commit(self) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        for (key, value) in self.writes {
+            match value {
+                Some(value) => batch.put(key, value),
+                None => batch.delete(key),
+            };
+        }
+        self.store.commit(&self.reads, batch)
+    }
+}
@@ -3,9 +3,14 @@ extern crate failure;
 extern crate log;
 extern crate uuid;
 
+use std::collections::BTreeMap;
 use std::fs;
+use std::io::Write;
+use std::ops::Bound;
 use std::panic;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
 
@@ -35,7 +40,7 @@ fn teardown(path: &str) {
 #[test]
 fn it_can_parse_config() {
     run_test(|_| {
-        let config = bitcask_rs::Config::new("tests/correct_config.yml");
+        let config = bitcask_rs::Config::new("tests/correct_config.yml").unwrap();
         assert_eq!(config.max_file_id, 1000000000);
         assert_eq!(config.path, PathBuf::from("bitcask/test/store"));
     })
@@ -43,10 +48,12 @@ fn it_can_parse_config() {
 
 #[test]
 fn it_cannot_parse_config() {
-    run_test(|_| {
-        let ret = std::panic::catch_unwind(|| bitcask_rs::Config::new("tests/wrong_config.yml"));
-        assert!(ret.is_err());
-    })
+    run_test(
+        |_| match bitcask_rs::Config::new("tests/wrong_config.yml") {
+            Err(bitcask_rs::Error::InvalidConfig(_)) => {}
+            _ => panic!("expected an invalid config error"),
+        },
+    )
 }
 
 #[test]
@@ -56,7 +63,7 @@ fn it_set_a_and_get_a() {
             .path(PathBuf::from(path))
             .build()
             .unwrap();
-        let mut bitcask = bitcask_rs::Bitcask::new(config);
+        let mut bitcask = bitcask_rs::Bitcask::new(config).unwrap();
         let key = b"1111";
         let set_ret = bitcask.set(key.to_vec(), vec![1, 2, 3]);
         assert!(set_ret.is_ok());
@@ -102,7 +109,7 @@ fn it_should_compact() {
             .path(PathBuf::from(path))
             .build()
             .unwrap();
-        let mut bitcask = bitcask_rs::Bitcask::new(config);
+        let mut bitcask = bitcask_rs::Bitcask::new(config).unwrap();
         populate_store(100, &mut bitcask);
         populate_store(50, &mut bitcask);
 
@@ -121,12 +128,12 @@ fn it_should_build_from_segment_file() {
             .build()
             .unwrap();
         {
-            let mut bitcask = bitcask_rs::Bitcask::new(config.clone());
+            let mut bitcask = bitcask_rs::Bitcask::new(config.clone()).unwrap();
             populate_store(100, &mut bitcask);
             populate_store(50, &mut bitcask);
         }
 
-        let bitcask = bitcask_rs::Bitcask::open(config);
+        let bitcask = bitcask_rs::Bitcask::open(config).unwrap();
         let ret = bitcask.get(b"1".as_ref());
         assert_eq!(ret.expect("u1").expect("u2"), vec![1, 2, 3, 4, 5]);
     })
@@ -139,7 +146,7 @@ fn it_should_access_from_multiple_thread() {
             .path(PathBuf::from(path))
             .build()
             .unwrap();
-        let mut bitcask = bitcask_rs::Bitcask::new(config.clone());
+        let mut bitcask = bitcask_rs::Bitcask::new(config.clone()).unwrap();
         populate_store(100, &mut bitcask);
         populate_store(50, &mut bitcask);
         bitcask.set(b"1".to_vec(), vec![1, 3, 4]).unwrap();
@@ -171,7 +178,7 @@ fn it_should_compact_while_reading_from_other_thread() {
             .path(PathBuf::from(path))
             .build()
             .unwrap();
-        let mut bitcask = bitcask_rs::Bitcask::new(config.clone());
+        let mut bitcask = bitcask_rs::Bitcask::new(config.clone()).unwrap();
         populate_store(100, &mut bitcask);
         populate_store(50, &mut bitcask);
 
@@ -192,3 +199,1284 @@ fn it_should_compact_while_reading_from_other_thread() {
         handler.join().unwrap();
     })
 }
+
+#[test]
+fn it_should_keep_timestamp_across_reopen() {
+    run_test(|path| {
+        let config = bitcask_rs::ConfigBuilder::default()
+            .path(PathBuf::from(path))
+            .build()
+            .unwrap();
+        let tstamp = {
+            let mut bitcask = bitcask_rs::Bitcask::new(config.clone()).unwrap();
+            bitcask.set(b"1".to_vec(), vec![1, 2, 3]).unwrap();
+            bitcask.set(b"2".to_vec(), vec![4, 5, 6]).unwrap();
+            bitcask.delete(b"2".to_vec()).unwrap();
+            assert_eq!(bitcask.timestamp(b"2".as_ref()), None);
+            bitcask.timestamp(b"1".as_ref()).expect("timestamp")
+        };
+        assert!(tstamp > 0);
+
+        let bitcask = bitcask_rs::Bitcask::open(config).unwrap();
+        assert_eq!(bitcask.timestamp(b"1".as_ref()), Some(tstamp));
+        assert_eq!(bitcask.timestamp(b"2".as_ref()), None);
+        assert_eq!(bitcask.get(b"1".as_ref()).unwrap(), Some(vec![1, 2, 3]));
+    })
+}
+
+#[test]
+fn it_should_expire_keys() {
+    run_test(|path| {
+        let config = bitcask_rs::ConfigBuilder::default()
+            .path(PathBuf::from(path))
+            .build()
+            .unwrap();
+        let mut bitcask = bitcask_rs::Bitcask::new(config.clone()).unwrap();
+        bitcask
+            .set_with_ttl(b"short".to_vec(), vec![1], Duration::from_millis(50))
+            .unwrap();
+        bitcask
+            .set_with_ttl(b"long".to_vec(), vec![2], Duration::from_secs(3600))
+            .unwrap();
+        bitcask.set(b"forever".to_vec(), vec![3]).unwrap();
+        assert!(bitcask
+            .expire(b"forever".to_vec(), Duration::from_secs(3600))
+            .unwrap());
+        // Too long to count in milliseconds, so it never expires.
+        bitcask
+            .set_with_ttl(b"huge".to_vec(), vec![4], Duration::from_secs(u64::MAX))
+            .unwrap();
+        assert_eq!(bitcask.get(b"huge".as_ref()).unwrap(), Some(vec![4]));
+        bitcask.delete(b"huge".to_vec()).unwrap();
+        assert!(!bitcask
+            .expire(b"missing".to_vec(), Duration::from_secs(1))
+            .unwrap());
+
+        assert_eq!(bitcask.get(b"short".as_ref()).unwrap(), Some(vec![1]));
+        assert!(bitcask.ttl(b"long".as_ref()).unwrap() > Duration::from_secs(3500));
+        assert!(bitcask.ttl(b"forever".as_ref()).is_some());
+        thread::sleep(Duration::from_millis(100));
+
+        assert_eq!(bitcask.get(b"short".as_ref()).unwrap(), None);
+        assert!(!bitcask.exists(b"short".as_ref()).unwrap());
+        assert_eq!(bitcask.ttl(b"short".as_ref()), None);
+        {
+            let keys = bitcask.keys();
+            let mut keys: Vec<&[u8]> = keys.into_iter().collect();
+            keys.sort();
+            assert_eq!(keys, vec![&b"forever"[..], &b"long"[..]]);
+        }
+
+        bitcask.merge(None).unwrap();
+        assert_eq!(bitcask.get(b"short".as_ref()).unwrap(), None);
+        assert_eq!(bitcask.get(b"forever".as_ref()).unwrap(), Some(vec![3]));
+
+        drop(bitcask);
+        let bitcask = bitcask_rs::Bitcask::open(config).unwrap();
+        assert_eq!(bitcask.get(b"short".as_ref()).unwrap(), None);
+        assert_eq!(bitcask.get(b"long".as_ref()).unwrap(), Some(vec![2]));
+    })
+}
+
+#[test]
+fn it_should_write_batch() {
+    run_test(|path| {
+        let config = bitcask_rs::ConfigBuilder::default()
+            .path(PathBuf::from(path))
+            .build()
+            .unwrap();
+        {
+            let mut bitcask = bitcask_rs::Bitcask::new(config.clone()).unwrap();
+            bitcask.set(b"1".to_vec(), vec![1]).unwrap();
+            let mut batch = bitcask_rs::WriteBatch::new();
+            batch
+                .put(b"2".to_vec(), vec![2])
+                .put(b"3".to_vec(), vec![3])
+                .delete(b"1".to_vec());
+            assert_eq!(batch.len(), 3);
+            bitcask.write(batch).unwrap();
+
+            assert_eq!(bitcask.get(b"1".as_ref()).unwrap(), None);
+            assert_eq!(bitcask.get(b"2".as_ref()).unwrap(), Some(vec![2]));
+            bitcask.set(b"4".to_vec(), vec![4]).unwrap();
+        }
+
+        let bitcask = bitcask_rs::Bitcask::open(config).unwrap();
+        assert_eq!(bitcask.get(b"1".as_ref()).unwrap(), None);
+        assert_eq!(bitcask.get(b"2".as_ref()).unwrap(), Some(vec![2]));
+        assert_eq!(bitcask.get(b"3".as_ref()).unwrap(), Some(vec![3]));
+        assert_eq!(bitcask.get(b"4".as_ref()).unwrap(), Some(vec![4]));
+    })
+}
+
+#[test]
+fn it_should_compare_and_swap() {
+    run_test(|path| {
+        let config = bitcask_rs::ConfigBuilder::default()
+            .path(PathBuf::from(path))
+            .build()
+            .unwrap();
+        let mut bitcask = bitcask_rs::Bitcask::new(config).unwrap();
+        assert!(bitcask
+            .compare_and_swap(b"1".to_vec(), None, Some(vec![1]))
+            .unwrap());
+        assert!(!bitcask
+            .compare_and_swap(b"1".to_vec(), None, Some(vec![2]))
+            .unwrap());
+        assert!(bitcask
+            .compare_and_swap(b"1".to_vec(), Some(&[1]), Some(vec![2]))
+            .unwrap());
+        assert_eq!(bitcask.get(b"1".as_ref()).unwrap(), Some(vec![2]));
+        assert!(bitcask
+            .compare_and_swap(b"1".to_vec(), Some(&[2]), None)
+            .unwrap());
+        assert_eq!(bitcask.get(b"1".as_ref()).unwrap(), None);
+    })
+}
+
+#[test]
+fn it_should_detect_transaction_conflict() {
+    run_test(|path| {
+        let config = bitcask_rs::ConfigBuilder::default()
+            .path(PathBuf::from(path))
+            .build()
+            .unwrap();
+        let mut bitcask = bitcask_rs::Bitcask::new(config).unwrap();
+        bitcask.set(b"a".to_vec(), vec![1]).unwrap();
+        bitcask.set(b"b".to_vec(), vec![1]).unwrap();
+
+        let mut txn = bitcask.transaction();
+        let a = txn.get(b"a").unwrap().unwrap();
+        txn.set(b"a".to_vec(), vec![a[0] - 1]);
+        txn.set(b"b".to_vec(), vec![2]);
+        assert_eq!(txn.get(b"b").unwrap(), Some(vec![2]));
+        txn.commit().unwrap();
+        assert_eq!(bitcask.get(b"a".as_ref()).unwrap(), Some(vec![0]));
+        assert_eq!(bitcask.get(b"b".as_ref()).unwrap(), Some(vec![2]));
+
+        let mut txn = bitcask.transaction();
+        assert_eq!(txn.get(b"a").unwrap(), Some(vec![0]));
+        txn.set(b"b".to_vec(), vec![3]);
+        bitcask.set(b"a".to_vec(), vec![5]).unwrap();
+        match txn.commit() {
+            Err(bitcask_rs::Error::Conflict { key }) => assert_eq!(key, b"a".to_vec()),
+            _ => panic!("expected a transaction conflict"),
+        }
+        assert_eq!(bitcask.get(b"b".as_ref()).unwrap(), Some(vec![2]));
+    })
+}
+
+#[test]
+fn it_should_not_conflict_on_records_moved_by_merge() {
+    run_test(|path| {
+        let config = bitcask_rs::ConfigBuilder::default()
+            .path(PathBuf::from(path))
+            .max_size_per_segment(64)
+            .build()
+            .unwrap();
+        let mut bitcask = bitcask_rs::Bitcask::new(config).unwrap();
+        bitcask.set(b"a".to_vec(), vec![1]).unwrap();
+        bitcask.set(b"b".to_vec(), vec![1]).unwrap();
+        bitcask.delete(b"b".to_vec()).unwrap();
+        for i in 0..10u8 {
+            bitcask.set(vec![i], vec![i; 16]).unwrap();
+        }
+
+        let mut txn = bitcask.transaction();
+        assert_eq!(txn.get(b"a").unwrap(), Some(vec![1]));
+        assert_eq!(txn.get(b"b").unwrap(), None);
+        txn.set(b"a".to_vec(), vec![2]);
+        // Moves `a` and drops the tombstone of `b`.
+        bitcask.merge(None).unwrap();
+        txn.commit().unwrap();
+        assert_eq!(bitcask.get(b"a".as_ref()).unwrap(), Some(vec![2]));
+    })
+}
+
+#[test]
+fn it_should_sync_by_policy() {
+    run_test(|path| {
+        for policy in vec![
+            bitcask_rs::SyncPolicy::EveryWrite,
+            bitcask_rs::SyncPolicy::EveryN(2),
+            bitcask_rs::SyncPolicy::Interval(Duration::from_millis(10)),
+        ] {
+            let config = bitcask_rs::ConfigBuilder::default()
+                .path(PathBuf::from(path))
+                .sync_policy(policy)
+                .build()
+                .unwrap();
+            {
+                let mut bitcask = bitcask_rs::Bitcask::open(config.clone()).unwrap();
+                populate_store(10, &mut bitcask);
+                thread::sleep(Duration::from_millis(20));
+                bitcask.sync().unwrap();
+            }
+            let bitcask = bitcask_rs::Bitcask::open(config).unwrap();
+            assert_eq!(
+                bitcask.get(b"1".as_ref()).unwrap(),
+                Some(vec![1, 2, 3, 4, 5])
+            );
+        }
+    })
+}
+
+#[test]
+fn it_should_refuse_zero_sync_policies() {
+    run_test(|path| {
+        for &policy in &[
+            bitcask_rs::SyncPolicy::EveryN(0),
+            bitcask_rs::SyncPolicy::Interval(Duration::from_secs(0)),
+        ] {
+            let config = bitcask_rs::ConfigBuilder::default()
+                .path(PathBuf::from(path))
+                .sync_policy(policy)
+                .build()
+                .unwrap();
+            match bitcask_rs::Bitcask::open(config) {
+                Err(bitcask_rs::Error::InvalidConfig(_)) => {}
+                _ => panic!("expected Error::InvalidConfig"),
+            }
+        }
+    })
+}
+
+#[test]
+fn it_should_group_commit_concurrent_writes() {
+    run_test(|path| {
+        let config = bitcask_rs::ConfigBuilder::default()
+            .path(PathBuf::from(path))
+            .sync_policy(bitcask_rs::SyncPolicy::EveryWrite)
+            .build()
+            .unwrap();
+        let bitcask = bitcask_rs::Bitcask::new(config).unwrap();
+        let handlers: Vec<_> = (0..8u8)
+            .map(|t| {
+                let mut bitcask = bitcask.clone();
+                thread::spawn(move || {
+                    for i in 0..50u8 {
+                        bitcask.set(vec![t, i], vec![i]).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handler in handlers {
+            handler.join().unwrap();
+        }
+
+        for t in 0..8u8 {
+            for i in 0..50u8 {
+                assert_eq!(bitcask.get(&vec![t, i]).unwrap(), Some(vec![i]));
+            }
+        }
+        // Writes that queued up behind a commit share its sync.
+        let syncs: u64 = bitcask.segment_stats().iter().map(|s| s.syncs).sum();
+        assert!(syncs > 0 && syncs < 400, "{} syncs", syncs);
+    })
+}
+
+#[test]
+fn it_should_fail_writes_when_file_ids_run_out() {
+    run_test(|path| {
+        let config = bitcask_rs::ConfigBuilder::default()
+            .path(PathBuf::from(path))
+            .max_size_per_segment(16)
+            .max_file_id(3)
+            .build()
+            .unwrap();
+        {
+            let mut bitcask = bitcask_rs::Bitcask::open(config.clone()).unwrap();
+            bitcask.set(vec![0], vec![0; 16]).unwrap();
+            bitcask.set(vec![1], vec![1; 16]).unwrap();
+            // Segments 1 and 2 are full and 3 is beyond `max_file_id`.
+            for _ in 0..2 {
+                match bitcask.set(vec![2], vec![2; 16]) {
+                    Err(bitcask_rs::Error::OutOfFileIds { max_file_id: 3 }) => {}
+                    ret => panic!("expected Error::OutOfFileIds, got {:?}", ret),
+                }
+            }
+            assert_eq!(bitcask.get(&vec![1]).unwrap(), Some(vec![1; 16]));
+            assert_eq!(bitcask.get(&vec![2]).unwrap(), None);
+        }
+
+        let mut config = config;
+        config.max_file_id = 10;
+        let mut bitcask = bitcask_rs::Bitcask::open(config).unwrap();
+        bitcask.set(vec![2], vec![2; 16]).unwrap();
+        for i in 0..3u8 {
+            assert_eq!(bitcask.get(&vec![i]).unwrap(), Some(vec![i; 16]));
+        }
+    })
+}
+
+#[test]
+fn it_should_truncate_torn_tail_on_open() {
+    run_test(|path| {
+        let config = bitcask_rs::ConfigBuilder::default()
+            .path(PathBuf::from(path))
+            .build()
+            .unwrap();
+        {
+            let mut bitcask = bitcask_rs::Bitcask::open(config.clone()).unwrap();
+            bitcask.set(b"1".to_vec(), b"a".to_vec()).unwrap();
+            bitcask.set(b"2".to_vec(), b"b".to_vec()).unwrap();
+        }
+        let segment_path = PathBuf::from(path).join("1.data");
+        let size = fs::metadata(&segment_path).unwrap().len();
+        {
+            // A put record cut off in the middle of its key.
+            let mut file = fs::OpenOptions::new()
+                .append(true)
+                .open(&segment_path)
+                .unwrap();
+            file.write_all(&[0, 5, 0, 3, b'3']).unwrap();
+        }
+
+        let mut bitcask = bitcask_rs::Bitcask::open(config).unwrap();
+        assert_eq!(fs::metadata(&segment_path).unwrap().len(), size);
+        assert_eq!(bitcask.get(b"1".as_ref()).unwrap(), Some(b"a".to_vec()));
+        assert_eq!(bitcask.get(b"2".as_ref()).unwrap(), Some(b"b".to_vec()));
+        bitcask.set(b"3".to_vec(), b"c".to_vec()).unwrap();
+        assert_eq!(bitcask.get(b"3".as_ref()).unwrap(), Some(b"c".to_vec()));
+    })
+}
+
+#[test]
+fn it_should_skip_stray_files_on_open() {
+    run_test(|path| {
+        let config = bitcask_rs::ConfigBuilder::default()
+            .path(PathBuf::from(path))
+            .build()
+            .unwrap();
+        {
+            let mut bitcask = bitcask_rs::Bitcask::open(config.clone()).unwrap();
+            bitcask.set(b"1".to_vec(), b"a".to_vec()).unwrap();
+        }
+        fs::write(PathBuf::from(path).join("README"), b"notes").unwrap();
+        fs::write(PathBuf::from(path).join("backup.data"), b"junk").unwrap();
+
+        let bitcask = bitcask_rs::Bitcask::open(config).unwrap();
+        assert_eq!(bitcask.get(b"1".as_ref()).unwrap(), Some(b"a".to_vec()));
+    })
+}
+
+#[test]
+fn it_should_reject_invalid_config() {
+    run_test(|path| {
+        let config = bitcask_rs::ConfigBuilder::default()
+            .path(PathBuf::from(path))
+            .max_file_id(10)
+            .min_merge_file_id(10)
+            .build()
+            .unwrap();
+        match bitcask_rs::Bitcask::open(config) {
+            Err(bitcask_rs::Error::InvalidConfig(_)) => {}
+            _ => panic!("expected an invalid config error"),
+        }
+    })
+}
+
+#[test]
+fn it_should_lock_store_directory() {
+    run_test(|path| {
+        let config = bitcask_rs::ConfigBuilder::default()
+            .path(PathBuf::from(path))
+            .build()
+            .unwrap();
+        let mut bitcask = bitcask_rs::Bitcask::open(config.clone()).unwrap();
+        bitcask.set(b"1".to_vec(), b"a".to_vec()).unwrap();
+        match bitcask_rs::Bitcask::open(config.clone()) {
+            Err(bitcask_rs::Error::Locked(_)) => {}
+            _ => panic!("expected the store to be locked"),
+        }
+
+        let mut reader = bitcask_rs::Bitcask::open_read_only(config.clone()).unwrap();
+        assert_eq!(reader.get(b"1".as_ref()).unwrap(), Some(b"a".to_vec()));
+        match reader.set(b"2".to_vec(), b"b".to_vec()) {
+            Err(bitcask_rs::Error::ReadOnly) => {}
+            _ => panic!("expected a read-only error"),
+        }
+        match reader.merge(None) {
+            Err(bitcask_rs::Error::ReadOnly) => {}
+            _ => panic!("expected a read-only error"),
+        }
+
+        drop(bitcask);
+        let bitcask = bitcask_rs::Bitcask::open(config).unwrap();
+        assert_eq!(bitcask.get(b"1".as_ref()).unwrap(), Some(b"a".to_vec()));
+    })
+}
+
+#[test]
+fn it_should_refresh_read_only_store() {
+    run_test(|path| {
+        let config = bitcask_rs::ConfigBuilder::default()
+            .path(PathBuf::from(path))
+            .max_size_per_segment(64)
+            .build()
+            .unwrap();
+        let mut bitcask = bitcask_rs::Bitcask::open(config.clone()).unwrap();
+        bitcask.set(b"1".to_vec(), b"a".to_vec()).unwrap();
+
+        let reader = bitcask_rs::Bitcask::open_read_only(config).unwrap();
+        for i in 0..20u8 {
+            bitcask.set(vec![i], vec![i; 16]).unwrap();
+        }
+        bitcask.delete(b"1".to_vec()).unwrap();
+        assert_eq!(reader.get(b"1".as_ref()).unwrap(), Some(b"a".to_vec()));
+        assert_eq!(reader.get(&vec![19u8]).unwrap(), None);
+
+        reader.refresh().unwrap();
+        assert_eq!(reader.get(b"1".as_ref()).unwrap(), None);
+        for i in 0..20u8 {
+            assert_eq!(reader.get(&vec![i]).unwrap(), Some(vec![i; 16]));
+        }
+    })
+}
+
+#[test]
+fn it_should_not_pair_merged_segment_with_stale_hint() {
+    run_test(|path| {
+        let config = bitcask_rs::ConfigBuilder::default()
+            .path(PathBuf::from(path))
+            .max_size_per_segment(16)
+            .build()
+            .unwrap();
+        {
+            let mut bitcask = bitcask_rs::Bitcask::open(config.clone()).unwrap();
+            bitcask.set(vec![0], vec![0; 16]).unwrap();
+            bitcask.set(vec![1], vec![1; 20]).unwrap();
+            bitcask.set(vec![2], vec![2; 16]).unwrap();
+        }
+        let dir = PathBuf::from(path);
+
+        // A merge swapping files in holds its manifest until it is done.
+        fs::write(dir.join("MERGE"), b"renames: []\nremoves: []\n").unwrap();
+        match bitcask_rs::Bitcask::open_read_only(config.clone()) {
+            Err(bitcask_rs::Error::MergeInProgress) => {}
+            ret => panic!("expected Error::MergeInProgress, got {:?}", ret.map(|_| ())),
+        }
+        fs::remove_file(dir.join("MERGE")).unwrap();
+
+        // Segment 1 renamed over before its hint was.
+        fs::copy(dir.join("2.data"), dir.join("1.data")).unwrap();
+        let reader = bitcask_rs::Bitcask::open_read_only(config).unwrap();
+        match reader.get(&vec![0]) {
+            Err(bitcask_rs::Error::Corruption { file_id: 1, .. }) => {}
+            ret => panic!("expected Error::Corruption, got {:?}", ret),
+        }
+        assert_eq!(reader.get(&vec![2]).unwrap(), Some(vec![2; 16]));
+    })
+}
+
+#[test]
+fn it_should_reopen_after_merge() {
+    run_test(|path| {
+        let config = bitcask_rs::ConfigBuilder::default()
+            .path(PathBuf::from(path))
+            .max_size_per_segment(64)
+            .build()
+            .unwrap();
+        {
+            let mut bitcask = bitcask_rs::Bitcask::open(config.clone()).unwrap();
+            for i in 0..20u8 {
+                bitcask.set(vec![i], vec![i; 16]).unwrap();
+                bitcask.set(vec![i], vec![i; 8]).unwrap();
+            }
+            bitcask.merge(None).unwrap();
+        }
+        assert!(!PathBuf::from(path).join("MERGE").exists());
+
+        let bitcask = bitcask_rs::Bitcask::open(config).unwrap();
+        for i in 0..20u8 {
+            assert_eq!(bitcask.get(&vec![i]).unwrap(), Some(vec![i; 8]));
+        }
+    })
+}
+
+#[test]
+fn it_should_merge_in_background() {
+    run_test(|path| {
+        let config = bitcask_rs::ConfigBuilder::default()
+            .path(PathBuf::from(path))
+            .max_size_per_segment(256)
+            .auto_merge(Some(bitcask_rs::AutoMerge {
+                fragmentation: 0.5,
+                check_interval: Duration::from_millis(20),
+                ..Default::default()
+            }))
+            .build()
+            .unwrap();
+        let mut bitcask = bitcask_rs::Bitcask::open(config).unwrap();
+        for round in 0..10u8 {
+            for i in 0..10u8 {
+                bitcask.set(vec![i], vec![round; 16]).unwrap();
+            }
+        }
+        let segment_count = || {
+            fs::read_dir(path)
+                .unwrap()
+                .filter(|e| e.as_ref().unwrap().path().extension().unwrap_or_default() == "data")
+                .count()
+        };
+        let before = segment_count();
+
+        let mut merged = false;
+        for _ in 0..100 {
+            thread::sleep(Duration::from_millis(20));
+            if segment_count() < before {
+                merged = true;
+                break;
+            }
+        }
+        assert!(merged);
+        for i in 0..10u8 {
+            assert_eq!(bitcask.get(&vec![i]).unwrap(), Some(vec![9; 16]));
+        }
+    })
+}
+
+#[test]
+fn it_should_bound_background_merges() {
+    run_test(|path| {
+        let config = bitcask_rs::ConfigBuilder::default()
+            .path(PathBuf::from(path))
+            .max_size_per_segment(256)
+            .auto_merge(Some(bitcask_rs::AutoMerge {
+                fragmentation: 0.5,
+                check_interval: Duration::from_millis(20),
+                limits: bitcask_rs::MergeLimits {
+                    max_segments: 1,
+                    ..Default::default()
+                },
+                ..Default::default()
+            }))
+            .build()
+            .unwrap();
+        let read_segment =
+            |file_id: u64| fs::read(PathBuf::from(path).join(format!("{}.data", file_id))).unwrap();
+        let mut bitcask = bitcask_rs::Bitcask::open(config.clone()).unwrap();
+        // Only the first segment ends up fragmented.
+        for round in 0..9u8 {
+            bitcask.set(vec![0], vec![round; 16]).unwrap();
+        }
+        for i in 1..40u8 {
+            bitcask.set(vec![i], vec![i; 16]).unwrap();
+        }
+        let first = read_segment(1);
+        let second = read_segment(2);
+
+        let mut merged = false;
+        for _ in 0..100 {
+            thread::sleep(Duration::from_millis(20));
+            if read_segment(1) != first {
+                merged = true;
+                break;
+            }
+        }
+        assert!(merged);
+        // Newer segments are left alone.
+        assert_eq!(read_segment(2), second);
+        assert_eq!(bitcask.get(&vec![0]).unwrap(), Some(vec![8; 16]));
+        for i in 1..40u8 {
+            assert_eq!(bitcask.get(&vec![i]).unwrap(), Some(vec![i; 16]));
+        }
+        drop(bitcask);
+
+        let mut config = config;
+        config.auto_merge = Some(bitcask_rs::AutoMerge {
+            check_interval: Duration::from_secs(0),
+            ..Default::default()
+        });
+        match bitcask_rs::Bitcask::open(config) {
+            Err(bitcask_rs::Error::InvalidConfig(_)) => {}
+            _ => panic!("expected Error::InvalidConfig"),
+        }
+    })
+}
+
+#[test]
+fn it_should_track_segment_stats() {
+    run_test(|path| {
+        let config = bitcask_rs::ConfigBuilder::default()
+            .path(PathBuf::from(path))
+            .build()
+            .unwrap();
+        let stats = {
+            let mut bitcask = bitcask_rs::Bitcask::open(config.clone()).unwrap();
+            bitcask.set(b"a".to_vec(), vec![1; 100]).unwrap();
+            bitcask.set(b"b".to_vec(), vec![2; 100]).unwrap();
+            bitcask.set(b"a".to_vec(), vec![3; 10]).unwrap();
+            bitcask.delete(b"b".to_vec()).unwrap();
+
+            let stats = bitcask.segment_stats();
+            assert_eq!(stats.len(), 1);
+            let stats = stats[0];
+            assert_eq!(stats.live_keys, 1);
+            assert_eq!(stats.dead_keys, 2);
+            assert_eq!(stats.tombstones, 1);
+            assert!(stats.dead_bytes > 200);
+            assert!(stats.live_bytes < 50);
+            assert!(stats.oldest_tstamp > 0);
+            assert!(stats.oldest_tstamp <= stats.newest_tstamp);
+            stats
+        };
+
+        let bitcask = bitcask_rs::Bitcask::open(config).unwrap();
+        let reopened = bitcask.segment_stats();
+        assert_eq!(reopened.len(), 2);
+        assert_eq!(reopened[0], stats);
+        assert_eq!(reopened[1].live_keys, 0);
+    })
+}
+
+#[test]
+fn it_should_merge_incrementally() {
+    run_test(|path| {
+        let config = bitcask_rs::ConfigBuilder::default()
+            .path(PathBuf::from(path))
+            .max_size_per_segment(64)
+            .build()
+            .unwrap();
+        let mut bitcask = bitcask_rs::Bitcask::open(config).unwrap();
+        for round in 0..5u8 {
+            for i in 0..20u8 {
+                bitcask.set(vec![i], vec![round; 8]).unwrap();
+            }
+        }
+        let before = bitcask.segment_stats().len();
+        let limits = bitcask_rs::MergeLimits {
+            max_segments: 2,
+            ..Default::default()
+        };
+
+        let handle = bitcask.merge_incremental(None, limits.clone()).unwrap();
+        handle.pause();
+        handle.cancel();
+        handle.wait().unwrap();
+        for i in 0..20u8 {
+            assert_eq!(bitcask.get(&vec![i]).unwrap(), Some(vec![4; 8]));
+        }
+
+        let handle = bitcask.merge_incremental(None, limits).unwrap();
+        handle.wait().unwrap();
+        assert!(bitcask.segment_stats().len() < before);
+        for i in 0..20u8 {
+            assert_eq!(bitcask.get(&vec![i]).unwrap(), Some(vec![4; 8]));
+        }
+    })
+}
+
+#[test]
+fn it_should_rate_limit_merge() {
+    run_test(|path| {
+        let config = bitcask_rs::ConfigBuilder::default()
+            .path(PathBuf::from(path))
+            .max_size_per_segment(1024)
+            .build()
+            .unwrap();
+        let mut bitcask = bitcask_rs::Bitcask::open(config).unwrap();
+        for round in 0..3u8 {
+            for i in 0..20u8 {
+                bitcask.set(vec![i], vec![round; 100]).unwrap();
+            }
+        }
+        match bitcask.merge_with_rate_limit(None, Some(0)) {
+            Err(bitcask_rs::Error::InvalidConfig(_)) => {}
+            ret => panic!("unexpected result {:?}", ret),
+        }
+
+        // About 6KB read and 2KB written, a second worth of it up front.
+        let start = std::time::Instant::now();
+        bitcask.merge_with_rate_limit(None, Some(4000)).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(800));
+        for i in 0..20u8 {
+            assert_eq!(bitcask.get(&vec![i]).unwrap(), Some(vec![2; 100]));
+        }
+    })
+}
+
+#[test]
+fn it_should_drop_tombstones_on_merge() {
+    run_test(|path| {
+        let config = bitcask_rs::ConfigBuilder::default()
+            .path(PathBuf::from(path))
+            .max_size_per_segment(64)
+            .build()
+            .unwrap();
+        {
+            let mut bitcask = bitcask_rs::Bitcask::open(config.clone()).unwrap();
+            for i in 0..10u8 {
+                bitcask.set(vec![i], vec![i; 16]).unwrap();
+            }
+            for i in 0..10u8 {
+                bitcask.delete(vec![i]).unwrap();
+            }
+            for i in 10..20u8 {
+                bitcask.set(vec![i], vec![i; 16]).unwrap();
+            }
+            // The values deleted sit below the merged segments, so their
+            // tombstones have to stay.
+            let since = bitcask.segment_stats()[4].file_id;
+            bitcask.merge(Some(since)).unwrap();
+            let tombstones: u64 = bitcask.segment_stats().iter().map(|s| s.tombstones).sum();
+            assert!(tombstones > 0);
+
+            bitcask.merge(None).unwrap();
+            let tombstones: u64 = bitcask.segment_stats().iter().map(|s| s.tombstones).sum();
+            assert_eq!(tombstones, 0);
+        }
+
+        let bitcask = bitcask_rs::Bitcask::open(config).unwrap();
+        for i in 0..10u8 {
+            assert_eq!(bitcask.get(&vec![i]).unwrap(), None);
+        }
+        for i in 10..20u8 {
+            assert_eq!(bitcask.get(&vec![i]).unwrap(), Some(vec![i; 16]));
+        }
+    })
+}
+
+#[test]
+fn it_should_read_through_mmap() {
+    run_test(|path| {
+        let config = bitcask_rs::ConfigBuilder::default()
+            .path(PathBuf::from(path))
+            .max_size_per_segment(64)
+            .mmap_reads(true)
+            .build()
+            .unwrap();
+        {
+            let mut bitcask = bitcask_rs::Bitcask::open(config.clone()).unwrap();
+            for i in 0..20u8 {
+                bitcask.set(vec![i], vec![i; 16]).unwrap();
+            }
+            // Rotated segments are mapped as soon as they are rotated.
+            assert_eq!(bitcask.get(&vec![0]).unwrap(), Some(vec![0; 16]));
+        }
+
+        let mut bitcask = bitcask_rs::Bitcask::open(config).unwrap();
+        for i in 0..20u8 {
+            assert_eq!(bitcask.get(&vec![i]).unwrap(), Some(vec![i; 16]));
+        }
+        for i in 0..10u8 {
+            bitcask.set(vec![i], vec![i; 8]).unwrap();
+        }
+        bitcask.merge(None).unwrap();
+        for i in 0..20u8 {
+            let len = if i < 10 { 8 } else { 16 };
+            assert_eq!(bitcask.get(&vec![i]).unwrap(), Some(vec![i; len]));
+        }
+    })
+}
+
+#[test]
+fn it_should_keep_borrowed_value_across_merge() {
+    run_test(|path| {
+        let config = bitcask_rs::ConfigBuilder::default()
+            .path(PathBuf::from(path))
+            .max_size_per_segment(64)
+            .mmap_reads(true)
+            .build()
+            .unwrap();
+        let mut bitcask = bitcask_rs::Bitcask::open(config).unwrap();
+        for i in 0..20u8 {
+            bitcask.set(vec![i], vec![i; 16]).unwrap();
+        }
+        for i in 0..20u8 {
+            bitcask.set(vec![i], vec![i; 8]).unwrap();
+        }
+        let value = bitcask.get_bytes(&vec![0u8]).unwrap().unwrap();
+        let active = bitcask.get_bytes(&vec![19u8]).unwrap().unwrap();
+
+        bitcask.merge(None).unwrap();
+        assert_eq!(&*value, &[0; 8][..]);
+        assert_eq!(active.into_vec(), vec![19; 8]);
+        assert!(bitcask.get_bytes(&vec![20u8]).unwrap().is_none());
+    })
+}
+
+#[test]
+fn it_should_scan_ordered_keys() {
+    run_test(|path| {
+        let config = bitcask_rs::ConfigBuilder::default()
+            .path(PathBuf::from(path))
+            .max_size_per_segment(64)
+            .keydir(bitcask_rs::KeyDirKind::Ordered)
+            .build()
+            .unwrap();
+        {
+            let mut bitcask = bitcask_rs::Bitcask::open(config.clone()).unwrap();
+            for i in (0..20u8).rev() {
+                bitcask.set(vec![b'a', i], vec![i]).unwrap();
+                bitcask.set(vec![b'b', i], vec![i]).unwrap();
+            }
+        }
+        let mut bitcask = bitcask_rs::Bitcask::open(config).unwrap();
+        // Newer writes land in the active segment and shadow older ones.
+        bitcask.set(vec![b'a', 3], vec![33]).unwrap();
+        bitcask.delete(vec![b'a', 4]).unwrap();
+
+        let collect = |range: &bitcask_rs::StoreRange| -> Vec<(Vec<u8>, Vec<u8>)> {
+            range
+                .iter()
+                .map(|r| r.map(|(k, v)| (k.to_vec(), v)).unwrap())
+                .collect()
+        };
+        let range = bitcask.range(vec![b'a', 2]..vec![b'a', 6]).unwrap();
+        assert_eq!(
+            collect(&range),
+            vec![
+                (vec![b'a', 2], vec![2]),
+                (vec![b'a', 3], vec![33]),
+                (vec![b'a', 5], vec![5]),
+            ]
+        );
+        drop(range);
+
+        let scan = bitcask.scan_prefix(b"b").unwrap();
+        let keys: Vec<Vec<u8>> = scan.iter().map(|r| r.unwrap().0.to_vec()).collect();
+        assert_eq!(keys, (0..20u8).map(|i| vec![b'b', i]).collect::<Vec<_>>());
+        let reversed: Vec<Vec<u8>> = scan.iter().rev().map(|r| r.unwrap().0.to_vec()).collect();
+        assert_eq!(
+            reversed,
+            (0..20u8).rev().map(|i| vec![b'b', i]).collect::<Vec<_>>()
+        );
+        // Both ends meet in the middle without repeating a key.
+        let mut iter = scan.iter();
+        assert_eq!(iter.next().unwrap().unwrap().0, &vec![b'b', 0]);
+        assert_eq!(iter.next_back().unwrap().unwrap().0, &vec![b'b', 19]);
+        assert_eq!(iter.count(), 18);
+
+        // Writers go ahead while a scan is open, which then reads the
+        // values they leave.
+        let mut writer = bitcask.clone();
+        writer.set(vec![b'b', 1], vec![11]).unwrap();
+        writer.delete(vec![b'b', 2]).unwrap();
+        let mut iter = scan.iter();
+        assert_eq!(iter.nth(1).unwrap().unwrap(), (&[b'b', 1][..], vec![11]));
+        assert_eq!(iter.next().unwrap().unwrap().0, &vec![b'b', 3]);
+        drop(scan);
+
+        bitcask.merge(None).unwrap();
+        let all = bitcask.range(..).unwrap();
+        assert_eq!(all.iter().count(), 38);
+    })
+}
+
+#[test]
+fn it_should_refuse_range_on_hashed_keys() {
+    run_test(|path| {
+        let config = bitcask_rs::ConfigBuilder::default()
+            .path(PathBuf::from(path))
+            .build()
+            .unwrap();
+        let bitcask = bitcask_rs::Bitcask::open(config).unwrap();
+        match bitcask.scan_prefix(b"a") {
+            Err(bitcask_rs::Error::Unordered) => {}
+            _ => panic!("expected Error::Unordered"),
+        };
+    })
+}
+
+#[test]
+fn it_should_pack_compact_keys() {
+    run_test(|path| {
+        let config = bitcask_rs::ConfigBuilder::default()
+            .path(PathBuf::from(path))
+            .max_size_per_segment(256)
+            .keydir(bitcask_rs::KeyDirKind::Compact)
+            .build()
+            .unwrap();
+        {
+            let mut bitcask = bitcask_rs::Bitcask::open(config.clone()).unwrap();
+            for i in 0..100u8 {
+                bitcask.set(vec![b'k', i], vec![i; 8]).unwrap();
+            }
+            for i in 0..50u8 {
+                bitcask.set(vec![b'k', i], vec![i; 4]).unwrap();
+            }
+            for i in 90..100u8 {
+                bitcask.delete(vec![b'k', i]).unwrap();
+            }
+            assert_eq!(bitcask.get(&vec![b'k', 0]).unwrap(), Some(vec![0; 4]));
+            assert_eq!(bitcask.get(&vec![b'k', 95]).unwrap(), None);
+        }
+
+        let mut bitcask = bitcask_rs::Bitcask::open(config).unwrap();
+        bitcask.merge(None).unwrap();
+        for i in 0..100u8 {
+            let expected = match i {
+                0..=49 => Some(vec![i; 4]),
+                50..=89 => Some(vec![i; 8]),
+                _ => None,
+            };
+            assert_eq!(bitcask.get(&vec![b'k', i]).unwrap(), expected);
+        }
+        assert_eq!(bitcask.keys().into_iter().count(), 90);
+
+        let stats = bitcask.keydir_stats();
+        assert_eq!(stats.keys, 90);
+        assert!(stats.bytes_per_key() > 0.0);
+    })
+}
+
+#[test]
+fn it_should_refuse_compact_keys_beyond_32_bits() {
+    run_test(|path| {
+        let config = bitcask_rs::ConfigBuilder::default()
+            .path(PathBuf::from(path))
+            .max_size_per_segment(1 << 33)
+            .keydir(bitcask_rs::KeyDirKind::Compact)
+            .build()
+            .unwrap();
+        match bitcask_rs::Bitcask::open(config) {
+            Err(bitcask_rs::Error::InvalidConfig(_)) => {}
+            _ => panic!("expected Error::InvalidConfig"),
+        };
+    })
+}
+
+#[test]
+fn it_should_refuse_values_too_long_for_compact_keys() {
+    run_test(|path| {
+        let config = bitcask_rs::ConfigBuilder::default()
+            .path(PathBuf::from(path))
+            .keydir(bitcask_rs::KeyDirKind::Compact)
+            .build()
+            .unwrap();
+        let mut bitcask = bitcask_rs::Bitcask::open(config).unwrap();
+        bitcask.set(b"a".to_vec(), b"1".to_vec()).unwrap();
+        let total_bytes = |bitcask: &bitcask_rs::Bitcask| -> u64 {
+            bitcask.segment_stats().iter().map(|s| s.total_bytes).sum()
+        };
+        let before = total_bytes(&bitcask);
+
+        // Never touched, so it takes no memory beyond its address space.
+        let huge = vec![0; 1 << 32];
+        match bitcask.set(b"b".to_vec(), huge) {
+            Err(bitcask_rs::Error::TooLarge { .. }) => {}
+            ret => panic!("expected Error::TooLarge, got {:?}", ret),
+        }
+        assert_eq!(total_bytes(&bitcask), before);
+        bitcask.set(b"b".to_vec(), b"2".to_vec()).unwrap();
+        assert_eq!(bitcask.get(&b"b".to_vec()).unwrap(), Some(b"2".to_vec()));
+    })
+}
+
+#[test]
+fn it_should_behave_alike_on_every_keydir() {
+    assert_eq!(
+        bitcask_rs::Config::default().keydir,
+        bitcask_rs::KeyDirKind::Hashed
+    );
+    for &kind in &[
+        bitcask_rs::KeyDirKind::Hashed,
+        bitcask_rs::KeyDirKind::Ordered,
+        bitcask_rs::KeyDirKind::Compact,
+    ] {
+        run_test(|path| {
+            let config = bitcask_rs::ConfigBuilder::default()
+                .path(PathBuf::from(path))
+                .max_size_per_segment(128)
+                .keydir(kind)
+                .build()
+                .unwrap();
+            {
+                let mut bitcask = bitcask_rs::Bitcask::open(config.clone()).unwrap();
+                for i in 0..30u8 {
+                    bitcask.set(vec![i], vec![i; 8]).unwrap();
+                }
+                for i in 0..10u8 {
+                    bitcask.delete(vec![i]).unwrap();
+                }
+            }
+
+            // Every segment is an older one after reopening, so the merge
+            // drops all the tombstones.
+            let mut bitcask = bitcask_rs::Bitcask::open(config).unwrap();
+            bitcask.merge(None).unwrap();
+            for i in 0..30u8 {
+                let expected = if i < 10 { None } else { Some(vec![i; 8]) };
+                assert_eq!(bitcask.get(&vec![i]).unwrap(), expected);
+            }
+            let mut keys: Vec<Vec<u8>> = bitcask.keys().into_iter().map(|k| k.to_vec()).collect();
+            keys.sort();
+            assert_eq!(keys, (10..30u8).map(|i| vec![i]).collect::<Vec<_>>());
+            assert_eq!(bitcask.keydir_stats().keys, 20);
+        })
+    }
+}
+
+/// A keydir kept outside the crate, counting the positions it is given.
+struct CountingKeyDir {
+    entries: BTreeMap<Vec<u8>, bitcask_rs::Position>,
+    inserts: Arc<AtomicUsize>,
+}
+
+impl bitcask_rs::KeyDir for CountingKeyDir {
+    fn get(&self, key: &[u8]) -> Option<bitcask_rs::Position> {
+        self.entries.get(key).cloned()
+    }
+
+    fn insert(&mut self, key: Vec<u8>, pos: bitcask_rs::Position) -> Option<bitcask_rs::Position> {
+        self.inserts.fetch_add(1, Ordering::SeqCst);
+        self.entries.insert(key, pos)
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Option<bitcask_rs::Position> {
+        self.entries.remove(key)
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear()
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (&'a [u8], bitcask_rs::Position)> + 'a> {
+        Box::new(self.entries.iter().map(|(k, pos)| (k.as_slice(), *pos)))
+    }
+
+    fn is_ordered(&self) -> bool {
+        true
+    }
+
+    fn range<'a>(
+        &'a self,
+        bounds: (Bound<Vec<u8>>, Bound<Vec<u8>>),
+    ) -> Option<bitcask_rs::OrderedEntries<'a>> {
+        Some(Box::new(
+            self.entries
+                .range(bounds)
+                .map(|(k, pos)| (k.as_slice(), *pos)),
+        ))
+    }
+
+    fn memory_usage(&self) -> usize {
+        0
+    }
+}
+
+#[test]
+fn it_should_plug_in_a_custom_keydir() {
+    run_test(|path| {
+        let inserts = Arc::new(AtomicUsize::new(0));
+        let factory = {
+            let inserts = inserts.clone();
+            bitcask_rs::KeyDirFactory::new(move || {
+                Box::new(CountingKeyDir {
+                    entries: BTreeMap::new(),
+                    inserts: inserts.clone(),
+                })
+            })
+        };
+        let config = bitcask_rs::ConfigBuilder::default()
+            .path(PathBuf::from(path))
+            .custom_keydir(Some(factory))
+            .build()
+            .unwrap();
+        {
+            let mut bitcask = bitcask_rs::Bitcask::open(config.clone()).unwrap();
+            for i in 0..10u8 {
+                bitcask.set(vec![i], vec![i]).unwrap();
+            }
+            bitcask.delete(vec![3]).unwrap();
+        }
+        assert_eq!(inserts.load(Ordering::SeqCst), 11);
+
+        let bitcask = bitcask_rs::Bitcask::open(config).unwrap();
+        assert!(inserts.load(Ordering::SeqCst) > 11);
+        assert_eq!(bitcask.get(&vec![3]).unwrap(), None);
+        let range = bitcask.range(vec![2]..vec![5]).unwrap();
+        let keys: Vec<Vec<u8>> = range.iter().map(|r| r.unwrap().0.to_vec()).collect();
+        assert_eq!(keys, vec![vec![2], vec![4]]);
+    })
+}
+
+#[test]
+fn it_should_open_from_keydir_snapshot() {
+    run_test(|path| {
+        let config = bitcask_rs::ConfigBuilder::default()
+            .path(PathBuf::from(path))
+            .max_size_per_segment(128)
+            .build()
+            .unwrap();
+        let snapshot_path = PathBuf::from(path).join("keydir.snapshot");
+        {
+            let mut bitcask = bitcask_rs::Bitcask::open(config.clone()).unwrap();
+            for i in 0..30u8 {
+                bitcask.set(vec![i], vec![i; 8]).unwrap();
+            }
+            bitcask.delete(vec![0]).unwrap();
+            bitcask.write_snapshot().unwrap();
+            // Written after the snapshot, so replayed on top of it.
+            for i in 10..20u8 {
+                bitcask.set(vec![i], vec![i; 4]).unwrap();
+            }
+            bitcask.delete(vec![1]).unwrap();
+        }
+        assert!(snapshot_path.exists());
+        // Segments the snapshot covers are not read, so their hints are not
+        // rebuilt either.
+        let hint_path = PathBuf::from(path).join("1.hint");
+        fs::remove_file(&hint_path).unwrap();
+
+        let check = |bitcask: &bitcask_rs::Bitcask| {
+            for i in 0..30u8 {
+                let expected = match i {
+                    0 | 1 => None,
+                    10..=19 => Some(vec![i; 4]),
+                    _ => Some(vec![i; 8]),
+                };
+                assert_eq!(bitcask.get(&vec![i]).unwrap(), expected);
+            }
+        };
+        let from_snapshot = bitcask_rs::Bitcask::open(config.clone()).unwrap();
+        check(&from_snapshot);
+        assert!(!hint_path.exists());
+        let stats = from_snapshot.segment_stats();
+        drop(from_snapshot);
+
+        // Rebuilt from every segment, the store ends up the same.
+        fs::rename(&snapshot_path, PathBuf::from(path).join("saved")).unwrap();
+        let rebuilt = bitcask_rs::Bitcask::open(config.clone()).unwrap();
+        check(&rebuilt);
+        assert!(hint_path.exists());
+        // Each open leaves an empty segment behind.
+        let rebuilt_stats: Vec<_> = rebuilt
+            .segment_stats()
+            .into_iter()
+            .filter(|s| s.total_bytes > 5)
+            .collect();
+        let stats: Vec<_> = stats.into_iter().filter(|s| s.total_bytes > 5).collect();
+        assert_eq!(rebuilt_stats, stats);
+        drop(rebuilt);
+
+        // A damaged snapshot is ignored.
+        let mut damaged = fs::read(PathBuf::from(path).join("saved")).unwrap();
+        let middle = damaged.len() / 2;
+        damaged[middle] ^= 0xff;
+        fs::write(&snapshot_path, damaged).unwrap();
+        let mut bitcask = bitcask_rs::Bitcask::open(config).unwrap();
+        check(&bitcask);
+
+        bitcask.merge(None).unwrap();
+        assert!(!snapshot_path.exists());
+        check(&bitcask);
+    })
+}
+
+#[test]
+fn it_should_snapshot_keys_written_to_the_active_segment() {
+    run_test(|path| {
+        let config = bitcask_rs::ConfigBuilder::default()
+            .path(PathBuf::from(path))
+            .max_size_per_segment(64)
+            .build()
+            .unwrap();
+        let newest_segment = || {
+            fs::read_dir(path)
+                .unwrap()
+                .map(|entry| entry.unwrap().path())
+                .filter(|p| p.extension().map_or(false, |ext| ext == "data"))
+                .max_by_key(|p| {
+                    let stem = p.file_stem().unwrap().to_str().unwrap();
+                    stem.parse::<u64>().unwrap()
+                })
+                .unwrap()
+        };
+        let (newest, covered) = {
+            let mut bitcask = bitcask_rs::Bitcask::open(config.clone()).unwrap();
+            bitcask.set(b"k".to_vec(), b"old".to_vec()).unwrap();
+            bitcask.set(b"filler".to_vec(), vec![0; 64]).unwrap();
+            bitcask.set(b"k".to_vec(), b"new".to_vec()).unwrap();
+            bitcask.write_snapshot().unwrap();
+            let newest = newest_segment();
+            let covered = fs::metadata(&newest).unwrap().len();
+            bitcask.set(b"later".to_vec(), b"1".to_vec()).unwrap();
+            // The snapshot ends part way into the active segment rather than
+            // starting a new one.
+            assert_eq!(newest_segment(), newest);
+            (newest, covered)
+        };
+        // Tear what was written after the snapshot, as a crash could.
+        fs::OpenOptions::new()
+            .write(true)
+            .open(&newest)
+            .unwrap()
+            .set_len(covered + 3)
+            .unwrap();
+
+        {
+            let bitcask = bitcask_rs::Bitcask::open(config.clone()).unwrap();
+            assert_eq!(bitcask.get(&b"k".to_vec()).unwrap(), Some(b"new".to_vec()));
+            assert_eq!(bitcask.get(&b"later".to_vec()).unwrap(), None);
+        }
+
+        // The hint of the segment the snapshot ended in was resumed from
+        // where the snapshot left it, so it still holds every key.
+        fs::remove_file(PathBuf::from(path).join("keydir.snapshot")).unwrap();
+        let bitcask = bitcask_rs::Bitcask::open(config).unwrap();
+        assert_eq!(bitcask.get(&b"k".to_vec()).unwrap(), Some(b"new".to_vec()));
+        assert_eq!(bitcask.get(&b"filler".to_vec()).unwrap(), Some(vec![0; 64]));
+    })
+}
+
+#[test]
+fn it_should_load_segments_in_parallel() {
+    run_test(|path| {
+        let config = bitcask_rs::ConfigBuilder::default()
+            .path(PathBuf::from(path))
+            .max_size_per_segment(128)
+            .build()
+            .unwrap();
+        assert!(config.load_threads >= 1);
+        {
+            let mut bitcask = bitcask_rs::Bitcask::open(config.clone()).unwrap();
+            for round in 0..4u8 {
+                for i in 0..40u8 {
+                    bitcask.set(vec![i], vec![round; i as usize % 7]).unwrap();
+                }
+                bitcask.delete(vec![round * 3]).unwrap();
+            }
+        }
+        // Segments without a hint are scanned instead.
+        for file_id in &[2, 5, 9] {
+            fs::remove_file(PathBuf::from(path).join(format!("{}.hint", file_id))).unwrap();
+        }
+
+        let mut opened = vec![];
+        for &load_threads in &[1, 3, 16] {
+            let mut config = config.clone();
+            config.load_threads = load_threads;
+            let bitcask = bitcask_rs::Bitcask::open(config).unwrap();
+            for i in 0..40u8 {
+                // Keys deleted in earlier rounds were set again.
+                let expected = match i {
+                    9 => None,
+                    _ => Some(vec![3; i as usize % 7]),
+                };
+                assert_eq!(bitcask.get(&vec![i]).unwrap(), expected);
+            }
+            // Each open leaves an empty segment behind.
+            let stats: Vec<_> = bitcask
+                .segment_stats()
+                .into_iter()
+                .filter(|s| s.total_bytes > 5)
+                .collect();
+            opened.push(stats);
+        }
+        assert_eq!(opened[0], opened[1]);
+        assert_eq!(opened[0], opened[2]);
+
+        let mut config = config;
+        config.load_threads = 0;
+        match bitcask_rs::Bitcask::open(config) {
+            Err(bitcask_rs::Error::InvalidConfig(_)) => {}
+            _ => panic!("expected Error::InvalidConfig"),
+        }
+    })
+}